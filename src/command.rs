@@ -1,6 +1,11 @@
-use ::{platform, BrokerServices, Policy};
+use ::{platform, BrokerServices, Policy, LearnedPolicy, ResourceUsage, ViolationAction, Zygote};
+use policy::{is_valid_parameter_name};
+use scratch::ScratchDir;
 
-use std::{io};
+use std::{io, env};
+use serde::{Serialize};
+use serde::de::{DeserializeOwned};
+use json;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
@@ -17,17 +22,44 @@ use std::process::{ExitStatus};
 /// 
 /// By default, `sandbox::Command` does not pass any environment variables to the child process.
 /// Environment variables can either be specified explicitly or be flagged for inheritance by
-/// `env_inherit`.
+/// `env_inherit` or `env_inherit_matching`.
+/// 
+/// Variables matching the policy's denied environment patterns (see `PolicyBuilder::deny_env`) are
+/// never passed. Spawning fails if such a variable is specified explicitly.
+/// 
+/// # Policy Parameters
+/// 
+/// Paths in the policy's rules may reference parameters such as `${JOB_DIR}`, which are bound for
+/// each command with `param`. `${EXE_DIR}` is bound to the directory containing the program unless
+/// it is set explicitly. Spawning fails if the policy references a parameter that is not bound.
 pub struct Command {
     pub(crate) program: PathBuf,
     pub(crate) policy: Policy,
     pub(crate) arguments: Vec<OsString>,
     pub(crate) envs: HashMap<OsString, EnvAction>,
+    pub(crate) env_inherit_patterns: Vec<String>,
     pub(crate) current_dir: Option<PathBuf>,
+    pub(crate) params: HashMap<String, OsString>,
+    pub(crate) lockdown_at_exec: bool,
+    pub(crate) kill_on_drop: bool,
+    pub(crate) new_process_group: bool,
+    pub(crate) scratch_dir: Option<u64>,
+}
+
+/// The parts of a `Command` that are resolved when it is spawned.
+pub(crate) struct Resolved {
+    pub(crate) rule_paths: Vec<PathBuf>,
+    pub(crate) envs: Vec<(OsString, OsString)>,
+    pub(crate) scratch_dir: Option<PathBuf>,
 }
 
 pub struct Child {
-    inner: platform::Child,
+    pub(crate) inner: platform::Child,
+    pub(crate) kill_on_drop: bool,
+    // Decides whether dying of the violation signal is attributed to the policy
+    pub(crate) violation_action: ViolationAction,
+    // Dropped after the child has been reaped, which deletes it
+    pub(crate) scratch_dir: Option<ScratchDir>,
 }
 
 impl Command {
@@ -37,7 +69,13 @@ impl Command {
             policy: policy.clone(),
             arguments: Default::default(),
             envs: Default::default(),
+            env_inherit_patterns: Default::default(),
             current_dir: None,
+            params: Default::default(),
+            lockdown_at_exec: false,
+            kill_on_drop: false,
+            new_process_group: false,
+            scratch_dir: None,
         }
     }
 
@@ -64,12 +102,14 @@ impl Command {
     }
 
     pub fn env_remove(&mut self, key: impl AsRef<OsStr>) -> &mut Self {
-        self.envs.remove(key.as_ref());
+        // Keep a tombstone so the variable isn't inherited through a pattern
+        self.envs.insert(key.as_ref().to_owned(), EnvAction::Remove);
         self
     }
 
     pub fn env_clear(&mut self) -> &mut Self {
         self.envs.clear();
+        self.env_inherit_patterns.clear();
         self
     }
 
@@ -78,19 +118,176 @@ impl Command {
         self
     }
 
+    /// Inherits every environment variable whose name matches `pattern`, in which `*` matches any
+    /// sequence of characters (e.g. `LC_*`).
+    /// 
+    /// Variables set by `env`, `env_inherit` or `env_remove` take precedence over patterns.
+    pub fn env_inherit_matching(&mut self, pattern: &str) -> &mut Self {
+        self.env_inherit_patterns.push(pattern.to_owned());
+        self
+    }
+
     pub fn current_dir(&mut self, dir: impl AsRef<Path>) -> &mut Self {
         self.current_dir = Some(dir.as_ref().to_owned());
         self
     }
 
+    /// Binds a value to a parameter referenced by the policy's rules.
+    /// 
+    /// Names may only contain ASCII letters, digits and underscores; spawning fails otherwise, as
+    /// it does when a parameter is unbound.
+    pub fn param(&mut self, name: &str, value: impl AsRef<OsStr>) -> &mut Self {
+        self.params.insert(name.to_owned(), value.as_ref().to_owned());
+        self
+    }
+
+    /// Enacts the policy in the child right before it executes the program, instead of when the
+    /// program calls `TargetServices::lockdown`.
+    /// 
+    /// This allows sandboxing programs that do not use this crate. The policy is extended with the
+    /// rights needed to execute the program and load it with the system's dynamic loader. The
+    /// program does not receive the sandbox's IPC channel. On macOS the policy is enacted by
+    /// `/usr/bin/sandbox-exec`, which then executes the program. Not supported on Windows.
+    pub fn lockdown_at_exec(&mut self, enabled: bool) -> &mut Self {
+        self.lockdown_at_exec = enabled;
+        self
+    }
+
+    /// Kills and reaps the child when its `Child` is dropped, if it is still running. Descendants
+    /// are killed too if the child was spawned with `new_process_group`.
+    /// 
+    /// Independently of this setting, sandbox-aware targets exit when the broker process does (on
+    /// macOS, where they watch for it from `sandbox::init`). Programs spawned with
+    /// `lockdown_at_exec` do not.
+    pub fn kill_on_drop(&mut self, enabled: bool) -> &mut Self {
+        self.kill_on_drop = enabled;
+        self
+    }
+
+    /// Places the child in a new process group, so `Child::kill_tree` can kill every process it
+    /// starts along with it.
+    /// 
+    /// Processes can leave their group with `setsid` or `setpgid`, which no policy can prevent on
+    /// macOS, so this only contains descendants that cooperate. The child no longer receives
+    /// signals sent to the broker's process group (e.g. from Ctrl-C in a terminal). Not supported
+    /// on Windows, or for zygotes.
+    pub fn new_process_group(&mut self, enabled: bool) -> &mut Self {
+        self.new_process_group = enabled;
+        self
+    }
+
+    /// Creates a private writable directory for the child, which is granted read and write access
+    /// in its policy and passed to it in `TMPDIR`.
+    /// 
+    /// Each spawned child gets a fresh directory, which is deleted once the child has been reaped by
+    /// `Child::wait` or `Child::try_wait`. The directory can't be deleted while the child could
+    /// still write to it, so dropping a `Child` that hasn't been reaped kills and reaps it first,
+    /// even without `kill_on_drop`. Descendants outside the child's process group (see
+    /// `new_process_group`) can survive it and recreate the directory. No filesystem with a size
+    /// limit is available on macOS, so there exceeding `size_limit` (in bytes) is only logged when
+    /// the directory is deleted. Not supported on Windows.
+    /// 
+    /// Spawning fails if `TMPDIR` is also set with `env` or `env_inherit`.
+    pub fn scratch_dir(&mut self, size_limit: u64) -> &mut Self {
+        self.scratch_dir = Some(size_limit);
+        self
+    }
+
     /**
      * Spawns a new process with the specified configuration, in a suspended state.
      * 
      * You must call `Child::run` once you are ready for the child process to start executing.
+     *
+     * On macOS, setuid and setgid programs are refused, and sandboxed processes cannot execute or
+     * create them regardless of their policy.
      */
     pub fn spawn(&mut self, services: &mut BrokerServices) -> io::Result<Child> {
-        let inner = platform::Child::spawn(services, self)?;
-        Ok(Child { inner })
+        let params = self.bound_params()?;
+        let rule_paths = self.policy.resolve_rules(&params)?;
+        if self.scratch_dir.is_some() {
+            let sets_tmpdir = self.envs.iter().any(|(k, action)| match action {
+                EnvAction::Remove => false,
+                // Compares names the way the platform does, e.g. without regard to case on Windows
+                _ => env_pattern_matches("TMPDIR", k),
+            });
+            if sets_tmpdir {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "TMPDIR cannot be set for a command with a scratch directory, which is passed in it"));
+            }
+        }
+        let scratch_dir = match self.scratch_dir {
+            Some(size_limit) => Some(ScratchDir::create(size_limit)?),
+            None => None,
+        };
+        let resolved = Resolved {
+            rule_paths,
+            envs: self.resolve_envs()?,
+            scratch_dir: scratch_dir.as_ref().map(|x| x.path().to_owned()),
+        };
+        let inner = platform::Child::spawn(services, self, resolved)?;
+        Ok(Child { inner, kill_on_drop: self.kill_on_drop, violation_action: self.policy.0.inner.violation_action(), scratch_dir })
+    }
+
+    /// Spawns a zygote running the program, from which targets can be created quickly with
+    /// `Zygote::fork`.
+    /// 
+    /// The program must call `sandbox::init` first thing, as for any other target; in the zygote,
+    /// `init` only returns in forked targets. The zygote itself is not locked down, so the
+    /// command's policy instead bounds the policies of forked targets, which must be subsets of it.
+    /// Cannot be combined with `lockdown_at_exec`, `new_process_group` or `scratch_dir`. Not
+    /// supported on Windows.
+    pub fn spawn_zygote(&mut self, services: &mut BrokerServices) -> io::Result<Zygote> {
+        if self.lockdown_at_exec || self.new_process_group || self.scratch_dir.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "zygotes cannot be spawned with lockdown at exec, a new process group or a scratch directory"));
+        }
+        let params = self.bound_params()?;
+        let rule_paths = self.policy.resolve_rules(&params)?;
+        let resolved = Resolved {
+            rule_paths: rule_paths.clone(),
+            envs: self.resolve_envs()?,
+            scratch_dir: None,
+        };
+        let inner = platform::Zygote::spawn(services, self, resolved)?;
+        Ok(Zygote::new(inner, self.policy.clone(), rule_paths, params, self.kill_on_drop))
+    }
+
+    fn bound_params(&self) -> io::Result<HashMap<String, OsString>> {
+        if let Some(name) = self.params.keys().find(|name| !is_valid_parameter_name(name)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid policy parameter name {:?}", name)));
+        }
+        let mut params = self.params.clone();
+        if !params.contains_key("EXE_DIR") {
+            if let Some(exe_dir) = self.program.parent() {
+                params.insert("EXE_DIR".to_owned(), exe_dir.as_os_str().to_owned());
+            }
+        }
+        Ok(params)
+    }
+
+    // Computes the environment passed to the child, enforcing the policy's denied variables
+    fn resolve_envs(&self) -> io::Result<Vec<(OsString, OsString)>> {
+        let mut envs = HashMap::new();
+        for (k, v) in env::vars_os() {
+            if self.env_inherit_patterns.iter().any(|pattern| env_pattern_matches(pattern, &k)) && !self.policy.env_denied(&k) {
+                envs.insert(k, v);
+            }
+        }
+        for (k, action) in self.envs.iter() {
+            match action {
+                EnvAction::Remove => {
+                    envs.remove(k);
+                },
+                _ if self.policy.env_denied(k) => {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("environment variable {:?} is denied by the policy", k)));
+                },
+                EnvAction::Value(value) => {
+                    envs.insert(k.clone(), value.clone());
+                },
+                EnvAction::Inherit => if let Some(value) = env::var_os(k) {
+                    envs.insert(k.clone(), value);
+                },
+            }
+        }
+        Ok(envs.into_iter().collect())
     }
 }
 
@@ -104,20 +301,228 @@ impl Child {
         self.inner.run()
     }
 
+    /// Waits for the child to exit, and returns its raw exit status.
+    /// 
+    /// This returns an `ExitStatus`, like `std::process::Child::wait`, so code that checks exit
+    /// codes and signals works unchanged. An `ExitStatus` can't say whether a signal came from the
+    /// sandbox, since that depends on the policy's `ViolationAction`, so use `wait_termination` to
+    /// get the classified `Termination` instead.
     pub fn wait(&mut self) -> io::Result<ExitStatus> {
-        self.inner.wait()
+        let status = self.inner.wait()?;
+        self.scratch_dir = None;
+        Ok(status)
     }
 
     pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
-        self.inner.try_wait()
+        let status = self.inner.try_wait()?;
+        if status.is_some() {
+            self.scratch_dir = None;
+        }
+        Ok(status)
+    }
+
+    /// Waits for the child to exit, and reports whether it exited by itself, was killed by a
+    /// signal, or was killed for violating its policy.
+    /// 
+    /// Violations are only fatal, and thus only reported, for policies built with
+    /// `ViolationAction::Kill` or `ViolationAction::Trap`. Otherwise the denied operation simply
+    /// fails in the child, and the signal the sandbox kills with (`SIGSYS` on macOS) is reported as
+    /// `Termination::Signaled` like any other. Under `Kill` or `Trap` a child that dies of that
+    /// signal for another reason is still reported as a violation.
+    pub fn wait_termination(&mut self) -> io::Result<Termination> {
+        let violation_action = self.violation_action;
+        self.wait().map(|status| Termination::from_status(status, violation_action))
     }
 
     pub fn kill(&mut self) -> io::Result<()> {
         self.inner.kill()
     }
+
+    /// Returns the details recorded when the child was killed for violating a policy built with
+    /// `ViolationAction::Trap`, once it has been reaped by `wait` or `try_wait`.
+    pub fn violation_report(&self) -> Option<ViolationReport> {
+        self.inner.violation_report()
+    }
+
+    /// Returns the resources the child consumed, once it has been reaped by `wait` or `try_wait`.
+    pub fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.inner.resource_usage()
+    }
+
+    /// Kills the child along with every process it started, and reports how.
+    /// 
+    /// Descendants can only be found if the child was spawned with `new_process_group`, in which
+    /// case the whole group is killed, even if the child itself has already exited. Descendants
+    /// that moved to another process group or session are not found. Otherwise only the child is
+    /// killed, as with `kill`, and `KillMechanism::ProcessOnly` is returned so callers can tell
+    /// descendants may have survived.
+    /// 
+    /// The group can only be killed until the child has been reaped by `wait` or `try_wait`, since
+    /// its ID may be reused afterwards. Call this before waiting for the child.
+    pub fn kill_tree(&mut self) -> io::Result<KillMechanism> {
+        self.inner.kill_tree()
+    }
+
+    /// Sends a message to the target over the sandbox's IPC channel, which it can receive with
+    /// `TargetServices::recv_message` after lockdown.
+    /// 
+    /// Messages are serialized as JSON and must fit in a single IPC message (16KiB). Only
+    /// available once the child is running, and not for children spawned with `lockdown_at_exec`.
+    pub fn send_message<T: Serialize>(&mut self, message: &T) -> io::Result<()> {
+        let message = json::to_string(message).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.inner.send_message(message)
+    }
+
+    /// Receives a message sent by the target with `TargetServices::send_message`, blocking until one
+    /// is available. Returns `None` if the target has closed the channel (e.g. because it exited).
+    pub fn recv_message<T: DeserializeOwned>(&mut self) -> io::Result<Option<T>> {
+        match self.inner.recv_message()? {
+            Some(message) => Ok(Some(json::from_str(&message).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieves the operations performed by a child spawned with a policy in learning mode, once
+    /// it has exited.
+    /// 
+    /// On macOS the sandbox reports the operations to the system log, which this reads with
+    /// `log show`. Reports reach the log asynchronously, so the last operations may be missing
+    /// right after the child exits, and the log may drop reports from a child performing many
+    /// operations at once. Operations performed before `TargetServices::lockdown` are not
+    /// recorded, since the policy doesn't apply to them.
+    pub fn learned_policy(&self) -> io::Result<LearnedPolicy> {
+        self.inner.learned_policy()
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        // The child could recreate its scratch directory after it is deleted, so it must not
+        // outlive it
+        if !self.kill_on_drop && self.scratch_dir.is_none() {
+            return;
+        }
+        // Descendants in the child's process group may outlive it, so kill them regardless. This
+        // must happen before the child is reaped, while the group's ID can't be reused.
+        if let Err(err) = self.inner.kill_tree() {
+            if let Ok(None) = self.inner.try_wait() {
+                error!("failed to kill sandboxed process {} on drop: {}", self.inner.id(), err);
+            }
+        }
+        if let Err(err) = self.inner.wait() {
+            warn!("error when waiting for sandboxed process {} to exit: {}", self.inner.id(), err);
+        }
+    }
+}
+
+/// How a child terminated, as reported by `Child::wait_termination`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Termination {
+    /// The child exited with the given code.
+    Exited(i32),
+    /// The child was killed by a signal that is not attributed to the sandbox.
+    Signaled(i32),
+    /// The child was killed for violating its policy.
+    Violation,
+}
+
+/// The system call a target was killed for, as recorded by the handler installed for
+/// `ViolationAction::Trap`.
+/// 
+/// On macOS the sandbox signals the process as the denied system call returns, so registers are
+/// captured from that point: argument registers that the call clobbers may no longer hold the
+/// original arguments.
+/// 
+/// Registers are only captured on x86_64. On other architectures, such as arm64, the report is
+/// empty: `syscall` and `syscall_name` are `None`, and the registers are zero. `Zygote::fork`
+/// rejects policies built with `ViolationAction::Trap`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ViolationReport {
+    /// The number of the denied system call, if it could be determined.
+    pub syscall: Option<u64>,
+    pub syscall_name: Option<&'static str>,
+    /// The system call argument registers, in calling convention order.
+    pub arguments: [u64; 6],
+    pub instruction_pointer: u64,
+}
+
+impl Termination {
+    pub(crate) fn from_status(status: ExitStatus, violation_action: ViolationAction) -> Self {
+        match platform::termination_signal(&status) {
+            // Policies that only deny operations never send the signal, so the child got it some
+            // other way (e.g. by calling a system call that doesn't exist)
+            Some(signal) if violation_action != ViolationAction::Deny && Some(signal) == platform::VIOLATION_SIGNAL => Termination::Violation,
+            Some(signal) => Termination::Signaled(signal),
+            None => Termination::Exited(status.code().expect("process neither exited nor was killed by a signal")),
+        }
+    }
+}
+
+/// How `Child::kill_tree` killed a child and its descendants.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KillMechanism {
+    /// Every process in the child's process group was killed.
+    ProcessGroup,
+    /// Only the child itself was killed, since its descendants could not be identified.
+    ProcessOnly,
 }
 
 pub(crate) enum EnvAction {
     Inherit,
     Value(OsString),
-}
\ No newline at end of file
+    Remove,
+}
+
+/// Matches an environment variable name against a pattern in which `*` matches any sequence of
+/// characters. Names are compared as the platform stores them, as bytes on Unix.
+#[cfg(unix)]
+pub(crate) fn env_pattern_matches(pattern: &str, name: &OsStr) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    wildcard_matches(pattern.as_bytes(), name.as_bytes(), &b'*')
+}
+
+/// Matches an environment variable name against a pattern in which `*` matches any sequence of
+/// characters. Names are compared as the platform stores them, as UTF-16, and without regard to
+/// case since Windows treats variable names as case-insensitive.
+#[cfg(windows)]
+pub(crate) fn env_pattern_matches(pattern: &str, name: &OsStr) -> bool {
+    use std::os::windows::ffi::OsStrExt;
+    let pattern: Vec<u16> = pattern.encode_utf16().map(fold_case).collect();
+    let name: Vec<u16> = name.encode_wide().map(fold_case).collect();
+    wildcard_matches(&pattern, &name, &(b'*' as u16))
+}
+
+#[cfg(windows)]
+fn fold_case(unit: u16) -> u16 {
+    // Surrogates and characters whose uppercase form isn't a single UTF-16 unit are left alone
+    let mut upper = ::std::char::from_u32(unit as u32).into_iter().flat_map(|c| c.to_uppercase());
+    match (upper.next(), upper.next()) {
+        (Some(c), None) if (c as u32) <= 0xFFFF => c as u32 as u16,
+        _ => unit,
+    }
+}
+
+// Only the most recent `*` is ever backtracked to, which suffices since it can absorb anything an
+// earlier one could. This bounds the work by the product of the lengths.
+fn wildcard_matches<T: PartialEq>(pattern: &[T], name: &[T], wildcard: &T) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The pattern position after the last `*`, and the name position that `*` has absorbed up to
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == *wildcard {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((after_wildcard, absorbed_to)) = backtrack {
+            // Let the `*` absorb one more element and retry the rest of the pattern from there
+            p = after_wildcard;
+            n = absorbed_to + 1;
+            backtrack = Some((after_wildcard, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| c == wildcard)
+}
@@ -0,0 +1,142 @@
+use ::{BrokerServices, TargetServices, Command, Child, Policy};
+
+use std::{io, env, panic};
+use std::any::Any;
+use std::collections::HashMap;
+
+use serde::{Serialize};
+use serde::de::{DeserializeOwned};
+use json;
+
+/// Functions that a sandboxed copy of the current executable can run on behalf of the broker.
+/// 
+/// The target builds a registry and passes it to `TargetServices::serve` after lockdown. The broker
+/// then invokes functions by name with `BrokerServices::call`. Arguments and results are serialized
+/// as JSON, and must fit in a single IPC message (16KiB).
+pub struct FunctionRegistry {
+    functions: HashMap<String, Box<Fn(&str) -> Result<String, String>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Request {
+    Call {
+        function: String,
+        argument: String,
+    },
+    Exit,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        FunctionRegistry {
+            functions: HashMap::new(),
+        }
+    }
+
+    pub fn register<A, R, F>(&mut self, name: &str, f: F) -> &mut Self where
+        A: DeserializeOwned,
+        R: Serialize,
+        F: Fn(A) -> R + 'static,
+    {
+        self.functions.insert(name.to_owned(), Box::new(move |argument| {
+            let argument: A = json::from_str(argument).map_err(|err| format!("failed to deserialize argument: {}", err))?;
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| f(argument))).map_err(panic_message)?;
+            json::to_string(&result).map_err(|err| format!("failed to serialize result: {}", err))
+        }));
+        self
+    }
+}
+
+impl BrokerServices {
+    /// Runs the function registered as `function` in a new sandboxed child of the current
+    /// executable, and returns its result.
+    /// 
+    /// The outer `Result` reports failures of the child process or IPC. The inner `Result` is `Err`
+    /// if the function panicked (containing the panic message) or was not registered.
+    pub fn call<A, R>(&mut self, policy: &Policy, function: &str, argument: &A) -> io::Result<Result<R, String>> where
+        A: Serialize,
+        R: DeserializeOwned,
+    {
+        let request = call_request(function, argument)?;
+        let mut child = server_command(policy)?.spawn(self)?;
+        child.run()?;
+        let result = call_in(&mut child, &request);
+        if result.is_ok() {
+            request_exit(&mut child)?;
+        } else {
+            // The child is in an unknown state, so don't bother asking nicely
+            let _ = child.kill();
+        }
+        child.wait()?;
+        decode_result(result?)
+    }
+}
+
+impl TargetServices {
+    /// Runs functions from `registry` as requested by the broker, until it asks the target to exit
+    /// or closes the channel.
+    /// 
+    /// Must be called after `lockdown`. Panics in functions are caught and reported to the broker.
+    pub fn serve(&mut self, registry: &FunctionRegistry) -> io::Result<()> {
+        while let Some(request) = self.recv_message::<Request>()? {
+            match request {
+                Request::Call { function, argument } => {
+                    debug!("running sandboxed function {:?}", function);
+                    let result = match registry.functions.get(&function) {
+                        Some(f) => f(&argument),
+                        None => Err(format!("no function named {:?} is registered", function)),
+                    };
+                    self.send_message(&result)?;
+                },
+                Request::Exit => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates a command that re-executes the current binary as a function server.
+pub(crate) fn server_command(policy: &Policy) -> io::Result<Command> {
+    let mut command = Command::new(env::current_exe()?, policy);
+    command.env_inherit("RUST_LOG");
+    Ok(command)
+}
+
+/// Creates the request for calling a function, failing if the argument cannot be serialized.
+pub(crate) fn call_request<A: Serialize>(function: &str, argument: &A) -> io::Result<Request> {
+    let argument = json::to_string(argument).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    Ok(Request::Call { function: function.to_owned(), argument })
+}
+
+/// Sends a call request to a child that is running `TargetServices::serve`, and receives the
+/// serialized result. Errors mean the child can no longer be relied on.
+pub(crate) fn call_in(child: &mut Child, request: &Request) -> io::Result<Result<String, String>> {
+    child.send_message(request)?;
+    match child.recv_message::<Result<String, String>>()? {
+        Some(result) => Ok(result),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sandboxed process exited before returning a result")),
+    }
+}
+
+/// Deserializes a result received by `call_in`.
+pub(crate) fn decode_result<R: DeserializeOwned>(result: Result<String, String>) -> io::Result<Result<R, String>> {
+    match result {
+        Ok(result) => Ok(Ok(json::from_str(&result).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?)),
+        Err(message) => Ok(Err(message)),
+    }
+}
+
+/// Asks a child running `TargetServices::serve` to exit.
+pub(crate) fn request_exit(child: &mut Child) -> io::Result<()> {
+    child.send_message(&Request::Exit)
+}
+
+fn panic_message(payload: Box<Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        format!("function panicked: {}", message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        format!("function panicked: {}", message)
+    } else {
+        "function panicked".to_owned()
+    }
+}
@@ -1,3 +1,26 @@
+//! Sandboxes processes with the facilities of the host operating system.
+//! 
+//! # Platform support
+//! 
+//! Backends exist for macOS (the Seatbelt sandbox) and Windows (restricted tokens, via crsio2).
+//! There is no Linux backend yet, so the following Linux mechanisms are out of scope until one is
+//! added:
+//! 
+//! - Confining every thread at lockdown with `SECCOMP_FILTER_FLAG_TSYNC`. Both existing backends
+//!   confine the whole process; the `threaded_lockdown` test checks this for any backend.
+//! - `PR_SET_PDEATHSIG` for `Command::kill_on_drop`. macOS targets watch their parent with a
+//!   kqueue instead.
+//! - `cgroup.kill` and PID namespace teardown for `Child::kill_tree`, which only uses process
+//!   groups.
+//! - cgroup stat files for `ResourceUsage`, which comes from `wait4` and `proc_pid_rusage` on
+//!   macOS.
+//! - seccomp filters, and with them a syscall filter DSL with argument matching, exporting and
+//!   disassembling the compiled BPF program, and named syscall groups such as `@file-system`.
+//!   Presets are defined by SBPL profiles on macOS and token levels on Windows, not syscalls.
+//! - A private root filesystem built with mount namespaces and `pivot_root`. On macOS, a
+//!   `ComputeOnly` policy with `allow_read` rules for the needed paths comes closest.
+//! - `PR_SET_NO_NEW_PRIVS`. macOS policies deny executing setuid and setgid programs instead.
+
 #[macro_use] extern crate log;
 #[macro_use] extern crate cfg_if;
 extern crate serde;
@@ -7,6 +30,7 @@ extern crate futures;
 extern crate tokio_reactor;
 extern crate tokio_current_thread;
 extern crate sandbox_ipc as ipc;
+extern crate sha2;
 
 cfg_if! {
     if #[cfg(target_os = "windows")] {
@@ -15,6 +39,7 @@ cfg_if! {
         extern crate crsio2;
     } else if #[cfg(target_os = "macos")] {
         extern crate libc;
+        extern crate mio;
     }
 }
 
@@ -26,19 +51,31 @@ mod tokio {
 mod services;
 mod policy;
 mod command;
+mod function;
+mod pool;
+mod scratch;
+mod supervisor;
+mod usage;
+mod zygote;
+pub mod warm_up;
 
 #[cfg_attr(target_os = "windows", path = "os/windows.rs")]
 #[cfg_attr(target_os = "macos", path = "os/macos/mod.rs")]
 mod platform;
 
 pub use services::{Services, BrokerServices, TargetServices};
-pub use command::Command;
-pub use policy::{Policy, PolicyBuilder, PolicyPreset};
+pub use command::{Command, Child, KillMechanism, Termination, ViolationReport};
+pub use function::FunctionRegistry;
+pub use pool::{WorkerPool, WorkerPoolConfig, WorkerPoolMetrics};
+pub use supervisor::{Supervisor, SupervisorEvent};
+pub use usage::ResourceUsage;
+pub use zygote::Zygote;
+pub use policy::{Policy, PolicyBuilder, PolicyPreset, PolicyFingerprint, LearnedPolicy, ViolationAction};
 
 pub mod os {
     #[cfg(target_os = "macos")]
     pub mod macos {
-        pub use platform::policy::{PolicyBuilderExt};
+        pub use platform::policy::{PolicyBuilderExt, Access};
     }
 }
 
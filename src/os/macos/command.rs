@@ -1,15 +1,23 @@
-use ::command::{Command, EnvAction};
+use ::{ResourceUsage, ViolationAction, ViolationReport};
+use ::command::{Command, Resolved, KillMechanism};
 use super::{CHANNEL_ENV_VAR};
 use super::services::{MAX_MESSAGE_SIZE, BrokerMessage, TargetMessage};
+use super::zygote::ZygoteShared;
+use super::usage::reap;
+use super::violation::read_report;
+use super::learning;
 
-use std::{io, env, mem, ptr, str, slice};
+use std::{io, fs, mem, ptr, str, slice};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::{self, Command as StdCommand, Child as StdChild, ExitStatus};
 use std::fs::File;
 use std::os::unix::prelude::*;
+use std::sync::Arc;
+use std::time::Instant;
 
 use futures::prelude::*;
-use ipc::{RawMessageChannel, MessageChannel, ProcessHandle};
+use ipc::{RawMessageChannel, ChildRawMessageChannel, MessageChannel, ProcessHandle};
 use tokio::current_thread::block_on_all;
 use json;
 use libc::{self, c_char, c_int, c_void};
@@ -18,50 +26,153 @@ pub struct Child {
     process_id: i32,
     error_rx: Option<File>,
     exit_status: Option<ExitStatus>,
+    resource_usage: Option<ResourceUsage>,
     resumed: bool,
     channel: Option<MessageChannel<BrokerMessage, TargetMessage>>,
-    policy: ::Policy,
+    // The policy sent to the target, with its parameters bound
+    policy: super::Policy,
+    // When a child in learning mode was spawned, so its reports can be found in the system log
+    learning_since: Option<Instant>,
+    // Owned by the generic Child, which deletes it
+    scratch_dir: Option<PathBuf>,
+    lockdown_at_exec: bool,
+    // Whether the child leads its own process group, which contains all its descendants
+    process_group: bool,
+    // Set for children forked by a zygote, which are not our children and must be managed through it
+    zygote: Option<Arc<ZygoteShared>>,
+    // The pipe the target reports violations over, for policies that trap them
+    violation_fd: Option<c_int>,
+    violation_rx: Option<File>,
+    violation_report: Option<ViolationReport>,
 }
 
+const SANDBOX_EXEC: &str = "/usr/bin/sandbox-exec";
+
 impl Child {
-    pub fn spawn(services: &mut ::BrokerServices, command: &mut Command) -> io::Result<Self> {
-        let mut std_command = StdCommand::new(&command.program);
+    pub fn spawn(services: &mut ::BrokerServices, command: &mut Command, resolved: Resolved) -> io::Result<Self> {
+        check_not_setugid(&command.program)?;
+
+        let learning_since = learning_since(&command.policy);
+
+        // Binding fails on parameter values the sandbox cannot take, which must be reported here
+        // rather than when the child is run
+        let policy = bind_policy(&command.policy, &resolved.rule_paths, resolved.scratch_dir.as_ref())?;
+        let exec_policy = if command.lockdown_at_exec {
+            Some(policy.clone())
+        } else {
+            None
+        };
+        // Enacting a policy can't be done between fork and exec, since it allocates. Instead
+        // sandbox-exec enacts it and then executes the program, in a process of its own.
+        let mut std_command = match exec_policy {
+            Some(mut policy) => {
+                policy.allow_exec(&command.program)?;
+                let mut std_command = StdCommand::new(SANDBOX_EXEC);
+                std_command
+                    .args(policy.sandbox_exec_arguments())
+                    .arg(&command.program);
+                std_command
+            },
+            None => StdCommand::new(&command.program),
+        };
         std_command.env_clear();
 
         std_command.args(&command.arguments);
         if let Some(current_dir) = command.current_dir.as_ref() {
             std_command.current_dir(current_dir);
         }
-        for (k, action) in command.envs.iter() {
-            match action {
-                EnvAction::Value(value) => {
-                    std_command.env(k, value);
-                },
-                EnvAction::Inherit => if let Some(value) = env::var_os(k) {
-                    std_command.env(k, value);
-                },
-            }
+        std_command.envs(resolved.envs);
+        if let Some(scratch_dir) = resolved.scratch_dir.as_ref() {
+            std_command.env("TMPDIR", scratch_dir);
         }
 
-        let (channel, (process_id, error_rx)) = RawMessageChannel::establish_with_child_custom(services.inner.event_loop.handle(), |child_channel| {
-            std_command
-                .env(CHANNEL_ENV_VAR, json::to_string(&child_channel).unwrap());
-            // TODO: this doesn't matter because ProcessHandles don't do anything on macOS, but we should probably make this clearer
-            Ok((ProcessHandle::current()?, do_spawn(&mut std_command, child_channel.as_raw_fd())?))
+        // The handler installed at lockdown can't exist in a program we exec into
+        let (violation_tx, violation_rx) = if command.policy.0.inner.violation_action() == ViolationAction::Trap && !command.lockdown_at_exec {
+            let (tx, rx) = anon_pipe()?;
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+        let violation_fd = violation_tx.as_ref().map(|x| x.as_raw_fd());
+
+        let (channel, (process_id, error_rx)) = if command.lockdown_at_exec {
+            // The program doesn't use the crate, so it gets neither the channel nor its variable
+            (None, do_spawn(&mut std_command, None, command.new_process_group, violation_fd)?)
+        } else {
+            let (channel, spawned) = RawMessageChannel::establish_with_child_custom(services.inner.event_loop.handle(), |child_channel| {
+                std_command
+                    .env(CHANNEL_ENV_VAR, json::to_string(&child_channel).unwrap());
+                // TODO: this doesn't matter because ProcessHandles don't do anything on macOS, but we should probably make this clearer
+                Ok((ProcessHandle::current()?, do_spawn(&mut std_command, Some(child_channel.as_raw_fd()), command.new_process_group, violation_fd)?))
+            })?;
+            (Some(MessageChannel::<BrokerMessage, TargetMessage>::from_raw(channel, MAX_MESSAGE_SIZE)?), spawned)
+        };
+        // Only the child may hold the write end, so the read end sees EOF once it exits
+        mem::drop(violation_tx);
+
+        Ok(Child {
+            process_id,
+            error_rx: Some(error_rx),
+            exit_status: None,
+            resource_usage: None,
+            resumed: false,
+            channel,
+            policy,
+            learning_since,
+            scratch_dir: resolved.scratch_dir,
+            lockdown_at_exec: command.lockdown_at_exec,
+            process_group: command.new_process_group,
+            zygote: None,
+            violation_fd,
+            violation_rx,
+            violation_report: None,
+        })
+    }
+
+    /// Creates a child by having a zygote fork itself. `fork` sends the request, and returns the
+    /// ID of the new process.
+    pub(in platform) fn fork_from_zygote<F>(services: &mut ::BrokerServices, policy: &::Policy, rule_paths: Vec<PathBuf>, zygote: Arc<ZygoteShared>, fork: F) -> io::Result<Self> where
+        F: FnOnce(&ChildRawMessageChannel) -> io::Result<i32>,
+    {
+        if policy.0.inner.violation_action() == ViolationAction::Trap {
+            // The pipe the handler reports over would have to be passed to the zygote along with
+            // the channel
+            return Err(io::Error::new(io::ErrorKind::Other, "policies that trap violations are not supported for targets forked by a zygote"));
+        }
+        let learning_since = learning_since(policy);
+        let policy = bind_policy(policy, &rule_paths, None)?;
+
+        let (channel, process_id) = RawMessageChannel::establish_with_child_custom(services.inner.event_loop.handle(), |child_channel| {
+            Ok((ProcessHandle::current()?, fork(&child_channel)?))
         })?;
 
         let channel = MessageChannel::<BrokerMessage, TargetMessage>::from_raw(channel, MAX_MESSAGE_SIZE)?;
 
         Ok(Child {
             process_id,
-            error_rx: Some(error_rx),
+            // Errors before the fork are reported by the zygote directly
+            error_rx: None,
             exit_status: None,
+            resource_usage: None,
             resumed: false,
             channel: Some(channel),
-            policy: command.policy.clone(),
+            policy,
+            learning_since,
+            scratch_dir: None,
+            lockdown_at_exec: false,
+            process_group: false,
+            zygote: Some(zygote),
+            violation_fd: None,
+            violation_rx: None,
+            violation_report: None,
         })
     }
 
+    /// The zygote that forked the child, if any.
+    pub(in platform) fn zygote(&self) -> Option<&Arc<ZygoteShared>> {
+        self.zygote.as_ref()
+    }
+
     pub fn id(&self) -> u32 {
         self.process_id as u32
     }
@@ -73,28 +184,43 @@ impl Child {
         if self.resumed {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "process has already been resumed"));
         }
-        let mut status: c_int = 0;
-        // Wait for process to either exit or suspend itself with SIGSTOP
-        unsafe { try_libc!(pid: libc::waitpid(self.process_id, &mut status, libc::WUNTRACED)); }
-        if !unsafe { libc::WIFSTOPPED(status) } {
-            let status = ExitStatus::from_raw(status);
-            self.exit_status = Some(status);
-            error!("spawned sandbox process exited before pausing for exec with status: {}", status);
-            if let Some(error) = self.check_early_error() {
-                return Err(error);
-            } else {
-                return Err(io::Error::new(io::ErrorKind::Other, "process has already exited"));
+        if let Some(zygote) = self.zygote.as_ref() {
+            // Only the zygote can wait for the process to stop itself
+            zygote.resume(self.process_id)?;
+        } else {
+            let mut status: c_int = 0;
+            // Wait for process to either exit or suspend itself with SIGSTOP
+            unsafe { try_libc!(pid: libc::waitpid(self.process_id, &mut status, libc::WUNTRACED)); }
+            if !unsafe { libc::WIFSTOPPED(status) } {
+                let status = ExitStatus::from_raw(status);
+                self.exit_status = Some(status);
+                error!("spawned sandbox process exited before pausing for exec with status: {}", status);
+                if let Some(error) = self.check_early_error() {
+                    return Err(error);
+                } else {
+                    return Err(io::Error::new(io::ErrorKind::Other, "process has already exited"));
+                }
+            }
+            // Resume process
+            unsafe {
+                try_libc!(libc::kill(self.process_id as i32, libc::SIGCONT));
             }
-        }
-        // Resume process
-        unsafe {
-            try_libc!(libc::kill(self.process_id as i32, libc::SIGCONT));
         }
         self.resumed = true;
 
+        if self.lockdown_at_exec {
+            // The policy was enacted before exec, and the program isn't expecting to receive it
+            return Ok(());
+        }
+
         // Send policy
+        let mut policy = self.policy.clone();
+        if let Some(fd) = self.violation_fd {
+            policy.set_violation_fd(fd);
+        }
         debug!("sending policy to sandboxed process");
-        block_on_all(self.channel.take().unwrap().send(BrokerMessage::PolicySpec(self.policy.0.inner.clone())))?;
+        let channel = block_on_all(self.channel.take().unwrap().send(BrokerMessage::PolicySpec(policy)))?;
+        self.channel = Some(channel);
         debug!("policy successfully sent");
 
         Ok(())
@@ -104,12 +230,17 @@ impl Child {
         if let Some(status) = self.exit_status {
             return Ok(status);
         }
-        let mut status: c_int = 0;
-        unsafe {
-            try_libc!(pid: libc::waitpid(self.process_id, &mut status, 0), "waitpid failed: {}");
+        if let Some(zygote) = self.zygote.as_ref() {
+            let (status, usage) = zygote.wait(self.process_id, true)?.unwrap();
+            self.exit_status = Some(status);
+            self.resource_usage = usage;
+            return Ok(status);
         }
+        let (_, status, usage) = reap(self.process_id, true)?.expect("blocking wait returned no process");
         let status = ExitStatus::from_raw(status);
         self.exit_status = Some(status);
+        self.resource_usage = Some(usage);
+        self.collect_violation_report();
         if let Some(error) = self.check_early_error() {
             return Err(error);
         }
@@ -120,19 +251,51 @@ impl Child {
         if let Some(status) = self.exit_status {
             return Ok(Some(status));
         }
-        let mut status: c_int = 0;
-        let pid = unsafe {
-            try_libc!(pid: libc::waitpid(self.process_id, &mut status, libc::WNOHANG), "waitpid failed: {}")
-        };
-        if pid == 0 {
-            Ok(None)
-        } else {
-            let status = ExitStatus::from_raw(status);
-            self.exit_status = Some(status);
-            if let Some(error) = self.check_early_error() {
-                return Err(error);
+        if let Some(zygote) = self.zygote.as_ref() {
+            return Ok(match zygote.wait(self.process_id, false)? {
+                Some((status, usage)) => {
+                    self.exit_status = Some(status);
+                    self.resource_usage = usage;
+                    Some(status)
+                },
+                None => None,
+            });
+        }
+        match reap(self.process_id, false)? {
+            None => Ok(None),
+            Some((_, status, usage)) => {
+                let status = ExitStatus::from_raw(status);
+                self.exit_status = Some(status);
+                self.resource_usage = Some(usage);
+                self.collect_violation_report();
+                if let Some(error) = self.check_early_error() {
+                    return Err(error);
+                }
+                Ok(Some(status))
+            },
+        }
+    }
+
+    /// Only available once the child has been reaped by `wait` or `try_wait`.
+    pub fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.resource_usage.clone()
+    }
+
+    pub fn violation_report(&self) -> Option<ViolationReport> {
+        self.violation_report.clone()
+    }
+
+    fn collect_violation_report(&mut self) {
+        if let Some(mut violation_rx) = self.violation_rx.take() {
+            self.violation_report = read_report(&mut violation_rx);
+            if let Some(report) = self.violation_report.as_ref() {
+                let syscall = match (report.syscall_name, report.syscall) {
+                    (Some(name), _) => name.to_owned(),
+                    (None, Some(number)) => format!("syscall {}", number),
+                    (None, None) => "an unknown syscall".to_owned(),
+                };
+                warn!("sandboxed process {} was killed for calling {} with arguments {:x?} at {:#x}", self.process_id, syscall, report.arguments, report.instruction_pointer);
             }
-            Ok(Some(status))
         }
     }
 
@@ -140,10 +303,74 @@ impl Child {
         if let Some(status) = self.exit_status {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "process has already exited"));
         }
+        if let Some(zygote) = self.zygote.as_ref() {
+            return zygote.kill(self.process_id);
+        }
         unsafe { try_libc!(libc::kill(self.process_id, libc::SIGKILL)); }
         Ok(())
     }
 
+    pub fn kill_tree(&mut self) -> io::Result<KillMechanism> {
+        if self.process_group {
+            // The group outlives the child if it has descendants, so this works after it has
+            // exited. Once it has been reaped and the group empties, the ID may be reused by an
+            // unrelated group.
+            if self.exit_status.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "process has already been reaped, so its process group can't be killed safely"));
+            }
+            unsafe { try_libc!(libc::killpg(self.process_id, libc::SIGKILL)); }
+            Ok(KillMechanism::ProcessGroup)
+        } else {
+            self.kill()?;
+            Ok(KillMechanism::ProcessOnly)
+        }
+    }
+
+    pub fn send_message(&mut self, message: String) -> io::Result<()> {
+        let channel = block_on_all(self.take_channel()?.send(BrokerMessage::User(message)))?;
+        self.channel = Some(channel);
+        Ok(())
+    }
+
+    pub fn recv_message(&mut self) -> io::Result<Option<String>> {
+        let (msg, channel) = block_on_all(self.take_channel()?.into_future().map_err(|(err, _)| err))?;
+        self.channel = Some(channel);
+        match msg {
+            Some(TargetMessage::User(message)) => Ok(Some(message)),
+            None => Ok(None),
+        }
+    }
+
+    /// Receives a message if one is available, without blocking. Must be called within a task,
+    /// which is notified once a message arrives.
+    pub(crate) fn poll_message(&mut self) -> io::Result<Async<Option<String>>> {
+        let mut channel = self.take_channel()?;
+        let result = channel.poll()?;
+        self.channel = Some(channel);
+        match result {
+            Async::Ready(Some(TargetMessage::User(message))) => Ok(Async::Ready(Some(message))),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+
+    // The channel is moved into each send or receive, and only put back if it succeeds
+    fn take_channel(&mut self) -> io::Result<MessageChannel<BrokerMessage, TargetMessage>> {
+        if !self.resumed || self.lockdown_at_exec {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "messages can only be exchanged with a running sandbox-aware target"));
+        }
+        self.channel.take().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "IPC channel to sandboxed process was lost after an earlier error"))
+    }
+
+    pub fn learned_policy(&self) -> io::Result<::LearnedPolicy> {
+        if self.exit_status.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "process has not exited yet"));
+        }
+        let since = self.learning_since
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "process was not spawned with a policy in learning mode"))?;
+        learning::learned_policy(self.process_id, since)
+    }
+
     // Checks if a pre-exec or exec error was reported. Should only be called when we know the
     // child has exited as otherwise it could block.
     fn check_early_error(&mut self) -> Option<io::Error> {
@@ -158,7 +385,34 @@ impl Child {
     }
 }
 
-fn do_spawn(command: &mut StdCommand, ipc_fd: c_int) -> io::Result<(i32, File)> {
+// The policy prevents targets from executing setuid or setgid programs, but the program itself is
+// executed before the policy applies
+pub(in platform) fn check_not_setugid(program: &Path) -> io::Result<()> {
+    if fs::metadata(program)?.permissions().mode() & (libc::S_ISUID | libc::S_ISGID) as u32 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "refusing to run a setuid or setgid program in the sandbox"));
+    }
+    Ok(())
+}
+
+fn learning_since(policy: &::Policy) -> Option<Instant> {
+    if policy.0.inner.learning_mode() {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+// Binds the per-child parameters of a policy
+fn bind_policy(policy: &::Policy, rule_paths: &[PathBuf], scratch_dir: Option<&PathBuf>) -> io::Result<super::Policy> {
+    let mut policy = policy.0.inner.clone();
+    policy.bind_rule_paths(rule_paths)?;
+    if let Some(scratch_dir) = scratch_dir {
+        policy.allow_scratch_dir(scratch_dir)?;
+    }
+    Ok(policy)
+}
+
+pub(in platform) fn do_spawn(command: &mut StdCommand, ipc_fd: Option<c_int>, new_process_group: bool, inherited_fd: Option<c_int>) -> io::Result<(i32, File)> {
     unsafe {
         // Any code that allocates needs to be done before fork (due to bugs in pthread_fork on some platforms)
         let fd_dir = ScopedDir(try_libc!(ptr: libc::opendir(b"/dev/fd\0".as_ptr() as *const c_char)));
@@ -167,7 +421,8 @@ fn do_spawn(command: &mut StdCommand, ipc_fd: c_int) -> io::Result<(i32, File)>
         match try_libc!(pid: libc::fork(), "fork failed: {}") {
             0 => {
                 mem::drop(error_rx);
-                let err = do_exec(command, fd_dir, &[0, 1, 2, ipc_fd, error_tx.as_raw_fd()]);
+                let inherited_fd = inherited_fd.unwrap_or(-1);
+                let err = do_exec(command, fd_dir, &[0, 1, 2, ipc_fd.unwrap_or(-1), error_tx.as_raw_fd(), inherited_fd], new_process_group, inherited_fd);
                 let errno = err.raw_os_error().unwrap_or(libc::EINVAL) as u32;
                 // If we get this far there was an error, emit the code to our parent via pipe
                 assert!(error_tx.write(&[
@@ -188,12 +443,23 @@ fn do_spawn(command: &mut StdCommand, ipc_fd: c_int) -> io::Result<(i32, File)>
     }
 }
 
-unsafe fn do_exec(command: &mut StdCommand, fd_dir: ScopedDir, excluded_fds: &[c_int]) -> io::Error {
+unsafe fn do_exec(command: &mut StdCommand, fd_dir: ScopedDir, excluded_fds: &[c_int], new_process_group: bool, inherited_fd: c_int) -> io::Error {
     if let Err(err) = before_exec(fd_dir, excluded_fds) {
         return err;
     }
 
+    // Unlike the error pipe, this one is meant to survive exec
+    if inherited_fd >= 0 && libc::fcntl(inherited_fd, libc::F_SETFD, 0) == -1 {
+        return io::Error::last_os_error();
+    }
+
+    // This happens before pausing, so the group exists by the time the broker resumes us
+    if new_process_group && libc::setpgid(0, 0) == -1 {
+        return io::Error::last_os_error();
+    }
+
     libc::raise(libc::SIGSTOP);
+
     command.exec()
 }
 
@@ -224,6 +490,13 @@ unsafe fn before_exec(fd_dir: ScopedDir, excluded_fds: &[c_int]) -> io::Result<(
     Ok(())
 }
 
+/// Kills a process by ID, for use when the `Child` is owned by another thread. The caller must
+/// ensure the process has not been reaped yet, so the ID cannot have been reused.
+pub fn kill_process(process_id: u32) -> io::Result<()> {
+    unsafe { try_libc!(libc::kill(process_id as i32, libc::SIGKILL)); }
+    Ok(())
+}
+
 fn anon_pipe() -> io::Result<(File, File)> {
     unsafe {
         let mut pipe_fds: [c_int; 2] = [0; 2];
@@ -0,0 +1,76 @@
+use ::LearnedPolicy;
+
+use std::io;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::Command as StdCommand;
+use std::time::Instant;
+
+use json;
+
+const LOG: &str = "/usr/bin/log";
+
+/// Collects the operations the sandbox reported for a process in learning mode, which runs under a
+/// profile that allows and reports everything, from the system log.
+pub(in platform) fn learned_policy(process_id: i32, since: Instant) -> io::Result<LearnedPolicy> {
+    // `log show` only looks back whole minutes
+    let minutes = since.elapsed().as_secs() / 60 + 1;
+    let output = StdCommand::new(LOG)
+        .args(&["show", "--style", "ndjson", "--last"])
+        .arg(format!("{}m", minutes))
+        .arg("--predicate")
+        .arg(format!(r#"sender == "Sandbox" AND eventMessage CONTAINS "({}) allow""#, process_id))
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::new(io::ErrorKind::Other, format!("failed to read sandbox reports from the system log: {}", stderr.trim())));
+    }
+
+    let mut network = false;
+    let mut read = BTreeSet::new();
+    let mut read_write = BTreeSet::new();
+    let mut other = BTreeSet::new();
+    for line in output.stdout.split(|&byte| byte == b'\n') {
+        // The last line summarizes the output rather than being an entry
+        let entry: json::Value = match json::from_slice(line) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let report = match entry.get("eventMessage").and_then(|message| message.as_str()).and_then(|message| parse_report(message, process_id)) {
+            Some(report) => report,
+            None => continue,
+        };
+        match report {
+            (operation, Some(path)) if operation.starts_with("file-write") && path.starts_with("/") => {
+                read_write.insert(PathBuf::from(path));
+            },
+            // A process can read the metadata of a directory leading to a file it opens, which
+            // would not need access to everything beneath the directory
+            (operation, Some(path)) if operation.starts_with("file-read") && operation != "file-read-metadata" && path.starts_with("/") => {
+                read.insert(PathBuf::from(path));
+            },
+            ("network-outbound", _) | ("system-socket", _) => network = true,
+            (operation, Some(argument)) => {
+                other.insert(format!("{} {}", operation, argument));
+            },
+            (operation, None) => {
+                other.insert(operation.to_owned());
+            },
+        }
+    }
+    Ok(LearnedPolicy::new(network, read, read_write, other))
+}
+
+// Reports look like `Sandbox: cat(1234) allow file-read-data /etc/hosts`, where the argument may
+// contain spaces and is absent for some operations
+fn parse_report(message: &str, process_id: i32) -> Option<(&str, Option<&str>)> {
+    let marker = format!("({}) ", process_id);
+    let rest = &message[(message.find(&marker)? + marker.len())..];
+    let mut words = rest.splitn(3, ' ');
+    if !words.next()?.starts_with("allow") {
+        return None;
+    }
+    let operation = words.next()?;
+    let argument = words.next().map(|argument| argument.trim()).filter(|argument| !argument.is_empty());
+    Some((operation, argument))
+}
@@ -4,12 +4,21 @@
 pub mod policy;
 mod services;
 mod command;
+mod zygote;
+mod usage;
+mod violation;
+mod learning;
+mod watch;
 
 pub use self::policy::{Policy, PolicyBuilder};
 pub use self::services::{BrokerServices, TargetServices};
-pub use self::command::{Child};
+pub use self::command::{Child, kill_process};
+pub use self::zygote::Zygote;
+pub use self::watch::ExitWatcher;
 
 use std::{io, env};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
 
 use json;
 use ipc::{MessageChannel, ChildRawMessageChannel, RawMessageChannel};
@@ -23,16 +32,39 @@ pub enum Services {
 // security feature
 // TODO: maybe use a static file descriptor number instead?
 const CHANNEL_ENV_VAR: &str = "SANDBOX_CHANNEL_ac15e9d0-52bd-4c49-a152-db8d6b8ea202";
+// Holds the file descriptor of a zygote's control socket
+const ZYGOTE_ENV_VAR: &str = "SANDBOX_ZYGOTE_ac15e9d0-52bd-4c49-a152-db8d6b8ea202";
+
+/// Environment variables used by the sandbox itself, which must never be set by callers.
+pub(crate) const RESERVED_ENV_VARS: &[&str] = &[CHANNEL_ENV_VAR, ZYGOTE_ENV_VAR];
+
+/// Returns the signal that terminated a process, if it was terminated by one.
+pub(crate) fn termination_signal(status: &ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+/// The signal that indicates a process was killed for violating its policy.
+pub(crate) const VIOLATION_SIGNAL: Option<i32> = Some(::libc::SIGSYS);
 
 pub fn init() -> io::Result<Services> {
-    if let Some(channel_str_os) = env::var_os(CHANNEL_ENV_VAR) {
+    let channel = if let Some(control_fd_os) = env::var_os(ZYGOTE_ENV_VAR) {
+        let control_fd = control_fd_os.to_str()
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid sandbox zygote socket passed in environment variable"))?;
+        env::remove_var(ZYGOTE_ENV_VAR);
+
+        // Only returns in processes forked from the zygote
+        zygote::run(control_fd)?
+    } else if let Some(channel_str_os) = env::var_os(CHANNEL_ENV_VAR) {
         let channel: ChildRawMessageChannel = channel_str_os.to_str()
             .and_then(|x| json::from_str(x).ok())
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid sandbox IPC channel passed in environment variable"))?;
         env::remove_var(CHANNEL_ENV_VAR);
-
-        Ok(Services::Target(TargetServices::new(channel)?))
+        channel
     } else {
-        Ok(Services::Broker(BrokerServices::new()?))
-    }
-}
\ No newline at end of file
+        return Ok(Services::Broker(BrokerServices::new()?));
+    };
+
+    services::exit_with_parent()?;
+    Ok(Services::Target(TargetServices::new(channel)?))
+}
@@ -1,22 +1,35 @@
-use ::{PolicyPreset};
+use ::{PolicyPreset, ViolationAction};
+use policy::{PathRule, PathAccess, encode_bytes, encode_length};
 
-use std::{io, ptr};
-use std::collections::HashMap;
+use std::{io, fs, ptr};
+use std::collections::{HashMap, BTreeMap};
 use std::fmt::Write;
-use std::ffi::{CStr, CString};
+use std::ffi::{CStr, CString, OsStr, OsString};
 use std::os::raw::{c_char, c_int};
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Policy {
     profile: String,
     parameters: HashMap<CString, CString>,
+    learning_mode: bool,
+    default_access: Access,
+    // What happens on a violation, which is always `Deny` in learning mode
+    violation_action: ViolationAction,
+    // The inherited pipe the target reports violations over, bound when the policy is sent
+    violation_fd: Option<c_int>,
 }
 
 pub struct PolicyBuilder {
     default_access: Access,
+    learning_mode: bool,
+    violation_action: ViolationAction,
+    rules: Vec<&'static str>,
+    interpreter_dir: Option<PathBuf>,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum Access {
     Allow,
     Deny,
@@ -24,39 +37,267 @@ pub enum Access {
 
 impl PolicyBuilder {
     pub fn new(broker: &mut ::BrokerServices, preset: PolicyPreset) -> Self {
+        let mut builder = PolicyBuilder {
+            default_access: Access::Deny,
+            learning_mode: false,
+            violation_action: ViolationAction::Deny,
+            rules: Vec::new(),
+            interpreter_dir: None,
+        };
         match preset {
-            PolicyPreset::ComputeOnly => {
-                PolicyBuilder {
-                    default_access: Access::Deny,
-                }
-            },
+            PolicyPreset::ComputeOnly => {},
             PolicyPreset::Unrestricted => {
-                PolicyBuilder {
-                    default_access: Access::Allow,
-                }
+                builder.default_access = Access::Allow;
+            },
+            PolicyPreset::ReadOnlyFilesystem => {
+                builder.rules.extend_from_slice(&[
+                    "(allow file-read*)",
+                    "(allow sysctl-read)",
+                ]);
+            },
+            PolicyPreset::NetworkClient => {
+                builder.rules.extend_from_slice(&[
+                    "(allow network-outbound)",
+                    "(allow system-socket)",
+                    r#"(allow file-read* (literal "/private/etc/hosts") (literal "/private/etc/resolv.conf") (literal "/private/var/run/resolv.conf"))"#,
+                    r#"(allow mach-lookup (global-name "com.apple.mDNSResponder"))"#,
+                    "(allow sysctl-read)",
+                ]);
+            },
+            PolicyPreset::FileConverter => {
+                builder.rules.extend_from_slice(&[
+                    r#"(allow file-read-data file-write-data (literal "/dev/null") (literal "/dev/stdin") (literal "/dev/stdout") (literal "/dev/stderr"))"#,
+                    r#"(allow file-ioctl (literal "/dev/null"))"#,
+                ]);
+            },
+            PolicyPreset::Interpreter { install_dir } => {
+                builder.rules.extend_from_slice(&[
+                    r#"(allow file-read* (subpath (param "SANDBOX_INTERPRETER_DIR")))"#,
+                    "(allow sysctl-read)",
+                ]);
+                builder.interpreter_dir = Some(install_dir);
             },
         }
+        builder
     }
 
-    pub fn build(self) -> io::Result<Policy> {
+    pub fn set_learning_mode(&mut self, enabled: bool) {
+        self.learning_mode = enabled;
+    }
+
+    pub fn set_violation_action(&mut self, action: ViolationAction) {
+        self.violation_action = action;
+    }
+
+    pub fn build(self, rules: &[PathRule]) -> io::Result<Policy> {
         let mut profile = String::new();
         let mut parameters = HashMap::new();
         writeln!(profile, "(version 1)").unwrap();
-        match self.default_access {
-            Access::Allow => writeln!(profile, "(allow default)").unwrap(),
-            Access::Deny => writeln!(profile, "(deny default)").unwrap(),
+        if self.learning_mode {
+            // The sandbox logs a report of every operation the process performs, which
+            // `Child::learned_policy` collects from the system log
+            writeln!(profile, "(allow default (with report))").unwrap();
+        } else {
+            match (self.default_access, self.violation_action) {
+                (Access::Allow, _) => writeln!(profile, "(allow default)").unwrap(),
+                (Access::Deny, ViolationAction::Deny) => writeln!(profile, "(deny default)").unwrap(),
+                (Access::Deny, ViolationAction::Kill) | (Access::Deny, ViolationAction::Trap) => writeln!(profile, "(deny default (with send-signal SIGSYS))").unwrap(),
+            }
+            if cfg!(debug_assertions) {
+                writeln!(profile, r#"(debug deny)"#).unwrap();
+            }
+            for rule in self.rules.iter() {
+                writeln!(profile, "{}", rule).unwrap();
+            }
+            // Rule paths are bound per child, so each one is a parameter resolved when the policy
+            // is sent
+            for (index, rule) in rules.iter().enumerate() {
+                match rule.access {
+                    PathAccess::Read => writeln!(profile, r#"(allow file-read* (subpath (param "{}")))"#, rule_parameter_name(index)).unwrap(),
+                    PathAccess::ReadWrite => writeln!(profile, r#"(allow file-read* file-write* (subpath (param "{}")))"#, rule_parameter_name(index)).unwrap(),
+                }
+            }
+        }
+        // Later rules take precedence, so this can't be overridden by the rules above, even in
+        // learning mode
+        for rule in NO_PRIVILEGE_GAIN_RULES.iter() {
+            writeln!(profile, "{}", rule).unwrap();
         }
-        if cfg!(debug_assertions) {
-            writeln!(profile, r#"(debug deny)"#).unwrap();
+        if let Some(interpreter_dir) = self.interpreter_dir.as_ref() {
+            // The sandbox matches against resolved paths, so symlinks like /var -> /private/var
+            // must be resolved beforehand
+            let interpreter_dir = fs::canonicalize(interpreter_dir)?;
+            parameters.insert(
+                CString::new(INTERPRETER_DIR_PARAM).unwrap(),
+                CString::new(interpreter_dir.into_os_string().into_vec())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "interpreter install directory contains a NUL character"))?,
+            );
         }
         Ok(Policy { 
             profile,
             parameters,
+            learning_mode: self.learning_mode,
+            default_access: self.default_access,
+            violation_action: if self.learning_mode { ViolationAction::Deny } else { self.violation_action },
+            violation_fd: None,
         })
     }
 }
 
+const INTERPRETER_DIR_PARAM: &str = "SANDBOX_INTERPRETER_DIR";
+const EXEC_PATH_PARAM: &str = "SANDBOX_EXEC_PATH";
+const SCRATCH_DIR_PARAM: &str = "SANDBOX_SCRATCH_DIR";
+
+// Prevents gaining privileges through setuid or setgid programs, whether existing ones or ones
+// the process creates. macOS has no equivalent of PR_SET_NO_NEW_PRIVS, and unlike other
+// operations these are denied even by Unrestricted policies.
+const NO_PRIVILEGE_GAIN_RULES: &[&str] = &[
+    "(deny process-exec* (file-mode #o4000))",
+    "(deny process-exec* (file-mode #o2000))",
+    "(deny file-write-setugid)",
+];
+
+// Rights needed to execute a program and load it with dyld, for policies enacted right before exec
+const EXEC_RULES: &[&str] = &[
+    r#"(allow process-exec (literal (param "SANDBOX_EXEC_PATH")))"#,
+    r#"(allow file-read* file-map-executable (literal (param "SANDBOX_EXEC_PATH")))"#,
+    r#"(allow file-read* file-map-executable (literal "/usr/lib/dyld") (subpath "/usr/lib") (subpath "/System/Library/Frameworks") (subpath "/System/Library/PrivateFrameworks") (subpath "/private/var/db/dyld") (subpath "/System/Library/dyld") (subpath "/System/Volumes/Preboot/Cryptexes/OS"))"#,
+    r#"(allow sysctl-read)"#,
+];
+
+pub(in platform) fn rule_parameter_name(index: usize) -> String {
+    format!("SANDBOX_RULE_{}", index)
+}
+
+fn exec_ancestor_parameter_name(index: usize) -> String {
+    format!("SANDBOX_EXEC_ANCESTOR_{}", index)
+}
+
 impl Policy {
+    pub(crate) fn learning_mode(&self) -> bool {
+        self.learning_mode
+    }
+
+    pub(crate) fn violation_action(&self) -> ViolationAction {
+        self.violation_action
+    }
+
+    pub(in platform) fn violation_fd(&self) -> Option<c_int> {
+        self.violation_fd
+    }
+
+    pub(in platform) fn set_violation_fd(&mut self, fd: c_int) {
+        self.violation_fd = Some(fd);
+    }
+
+    /// Binds the paths of the policy's rules, as resolved for a particular process.
+    pub(in platform) fn bind_rule_paths(&mut self, rule_paths: &[PathBuf]) -> io::Result<()> {
+        for (index, path) in rule_paths.iter().enumerate() {
+            let path = Policy::bound_rule_path(path);
+            self.set_parameter(&rule_parameter_name(index), path.as_os_str().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The path a rule's path is bound as, which is what the sandbox matches against.
+    pub(crate) fn bound_rule_path(path: &Path) -> PathBuf {
+        // The sandbox matches against resolved paths, so symlinks like /var -> /private/var must be
+        // resolved. Paths that don't exist yet (e.g. outputs) are resolved as far as they exist.
+        let mut existing = path;
+        let mut rest = Vec::new();
+        loop {
+            if let Ok(resolved) = fs::canonicalize(existing) {
+                return rest.iter().rev().fold(resolved, |path, name| path.join(name));
+            }
+            match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    rest.push(name);
+                    existing = parent;
+                },
+                _ => return path.to_owned(),
+            }
+        }
+    }
+
+    pub(in platform) fn set_parameter(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        let value = CString::new(value)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("value of policy parameter {} contains a NUL character", key)))?;
+        // Names are generated by this crate
+        self.parameters.insert(CString::new(key).expect("invalid characters in parameter name"), value);
+        Ok(())
+    }
+
+    /// Whether the platform-specific options allow no more than `outer`'s. Presets and rules are
+    /// compared by `::Policy::check_subset_of`, on paths resolved by `bound_rule_path`.
+    pub(crate) fn is_subset_of(&self, outer: &Policy) -> bool {
+        !(self.default_access == Access::Allow && outer.default_access == Access::Deny)
+    }
+
+    /// Writes the state that isn't determined by the generic policy, for `PolicyFingerprint`.
+    pub(crate) fn encode_fingerprint(&self, encoding: &mut Vec<u8>) {
+        encode_bytes(encoding, b"macos");
+        match self.default_access {
+            Access::Allow => encode_bytes(encoding, b"allow-default"),
+            Access::Deny => encode_bytes(encoding, b"deny-default"),
+        }
+        // Debug builds log denials, which is part of the compiled profile
+        encode_bytes(encoding, if cfg!(debug_assertions) { b"debug-deny" } else { b"no-debug" });
+        let parameters: BTreeMap<&CString, &CString> = self.parameters.iter().collect();
+        encode_length(encoding, parameters.len());
+        for (name, value) in parameters {
+            encode_bytes(encoding, name.as_bytes());
+            encode_bytes(encoding, value.as_bytes());
+        }
+    }
+
+    /// Extends the policy so that it can be enacted right before executing `program`.
+    pub(in platform) fn allow_exec(&mut self, program: &Path) -> io::Result<()> {
+        for rule in EXEC_RULES.iter() {
+            writeln!(self.profile, "{}", rule).unwrap();
+        }
+        let program = fs::canonicalize(program)?;
+        self.set_parameter(EXEC_PATH_PARAM, program.as_os_str().as_bytes())?;
+        // The loader looks up the directories leading to the program, but nothing else needs their
+        // metadata
+        for (index, ancestor) in program.ancestors().skip(1).enumerate() {
+            let name = exec_ancestor_parameter_name(index);
+            writeln!(self.profile, r#"(allow file-read-metadata (literal (param "{}")))"#, name).unwrap();
+            self.set_parameter(&name, ancestor.as_os_str().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Extends the policy to allow reading and writing a child's scratch directory.
+    pub(in platform) fn allow_scratch_dir(&mut self, dir: &Path) -> io::Result<()> {
+        if self.learning_mode {
+            // Everything is already allowed
+            return Ok(());
+        }
+        writeln!(self.profile, r#"(allow file-read* file-write* (subpath (param "{}")))"#, SCRATCH_DIR_PARAM).unwrap();
+        // Keep these last, so the rule above doesn't allow creating setuid programs
+        for rule in NO_PRIVILEGE_GAIN_RULES.iter() {
+            writeln!(self.profile, "{}", rule).unwrap();
+        }
+        self.set_parameter(SCRATCH_DIR_PARAM, dir.as_os_str().as_bytes())
+    }
+
+    /// The arguments for `sandbox-exec` that enact the policy before it executes the program
+    /// following them.
+    pub(in platform) fn sandbox_exec_arguments(&self) -> Vec<OsString> {
+        let mut arguments = vec![OsString::from("-p"), OsString::from(&self.profile)];
+        // HashMap iteration order is random, so sort the parameters first
+        let parameters: BTreeMap<&CString, &CString> = self.parameters.iter().collect();
+        for (k, v) in parameters {
+            let mut definition = OsString::from(OsStr::from_bytes(k.as_bytes()));
+            definition.push("=");
+            definition.push(OsStr::from_bytes(v.as_bytes()));
+            arguments.push(OsString::from("-D"));
+            arguments.push(definition);
+        }
+        arguments.push(OsString::from("--"));
+        arguments
+    }
+
     pub(crate) fn enact(&self) -> io::Result<()> {
         let profile_cstr = CString::new(self.profile.clone()).expect("invalid characters in profile");
         let mut params_list = Vec::new();
@@ -1,11 +1,14 @@
 use super::policy::Policy;
+use super::violation;
 
-use std::{io, process, panic};
+use std::{io, mem, process, panic, ptr, thread};
+use std::path::PathBuf;
 
 use futures::prelude::*;
 use tokio::reactor::{Reactor, Background as BackgroundReactor};
 use tokio::current_thread::block_on_all;
 use ipc::{MessageChannel, ChildRawMessageChannel};
+use libc;
 
 pub struct BrokerServices {
     pub(in platform) event_loop: BackgroundReactor,
@@ -14,6 +17,7 @@ pub struct BrokerServices {
 pub struct TargetServices {
     event_loop: BackgroundReactor,
     channel: Option<MessageChannel<TargetMessage, BrokerMessage>>,
+    locked_down: bool,
 }
 
 impl BrokerServices {
@@ -23,6 +27,21 @@ impl BrokerServices {
             event_loop
         })
     }
+
+    /// Binds `policy` for enacting on the calling process, failing without side effects if it
+    /// cannot be.
+    pub fn prepare_lockdown_self(&self, policy: &::Policy, rule_paths: &[PathBuf]) -> io::Result<Policy> {
+        if policy.0.inner.learning_mode() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "policies in learning mode can only be used to spawn targets"));
+        }
+        let mut policy = policy.0.inner.clone();
+        policy.bind_rule_paths(rule_paths)?;
+        Ok(policy)
+    }
+
+    pub fn lockdown_self(&mut self, policy: &Policy) -> io::Result<()> {
+        policy.enact()
+    }
 }
 
 impl TargetServices {
@@ -32,23 +51,103 @@ impl TargetServices {
         Ok(TargetServices {
             event_loop,
             channel: Some(channel),
+            locked_down: false,
         })
     }
 
     pub fn lockdown(&mut self) -> io::Result<()> {
-        if self.channel.is_none() {
+        if self.locked_down {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, "sandboxed process has already been locked down"));
         }
         debug!("receiving policy from broker");
-        let (msg, channel) = block_on_all(self.channel.take().unwrap().into_future().map_err(|(err, _)| err))?;
+        let (msg, channel) = block_on_all(self.take_channel()?.into_future().map_err(|(err, _)| err))?;
         debug!("policy has been received");
+        self.channel = Some(channel);
         if let Some(BrokerMessage::PolicySpec(policy)) = msg {
+            // The sandbox is attached to the process rather than the calling thread, so the
+            // reactor thread is confined as well
+            if let Some(fd) = policy.violation_fd() {
+                violation::install_trap_handler(fd)?;
+            }
             policy.enact()?;
         } else {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid initial message from broker"));
         }
+        self.locked_down = true;
         Ok(())
     }
+
+    pub fn send_message(&mut self, message: String) -> io::Result<()> {
+        if !self.locked_down {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "messages can only be sent after lockdown"));
+        }
+        let channel = block_on_all(self.take_channel()?.send(TargetMessage::User(message)))?;
+        self.channel = Some(channel);
+        Ok(())
+    }
+
+    pub fn recv_message(&mut self) -> io::Result<Option<String>> {
+        if !self.locked_down {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "messages can only be received after lockdown"));
+        }
+        let (msg, channel) = block_on_all(self.take_channel()?.into_future().map_err(|(err, _)| err))?;
+        self.channel = Some(channel);
+        match msg {
+            Some(BrokerMessage::User(message)) => Ok(Some(message)),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected message from broker")),
+            None => Ok(None),
+        }
+    }
+
+    // The channel is moved into each send or receive, and only put back if it succeeds
+    fn take_channel(&mut self) -> io::Result<MessageChannel<TargetMessage, BrokerMessage>> {
+        self.channel.take().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "IPC channel to broker was lost after an earlier error"))
+    }
+}
+
+/// Starts a thread that exits the process as soon as its parent (the broker, or the zygote it was
+/// forked from) exits, since macOS has no equivalent of `PR_SET_PDEATHSIG`.
+pub(in platform) fn exit_with_parent() -> io::Result<()> {
+    unsafe {
+        let parent = libc::getppid();
+        // Orphans are adopted by launchd, which is never a broker, so this can't miss a parent that
+        // exited before we started watching it
+        if parent == 1 {
+            error!("broker exited before sandboxed process started");
+            libc::_exit(1);
+        }
+        let queue = try_libc!(fd: libc::kqueue());
+        let mut event: libc::kevent = mem::zeroed();
+        event.ident = parent as usize;
+        event.filter = libc::EVFILT_PROC;
+        event.flags = libc::EV_ADD | libc::EV_ONESHOT;
+        event.fflags = libc::NOTE_EXIT;
+        if libc::kevent(queue, &event, 1, ptr::null_mut(), 0, ptr::null()) == -1 {
+            let err = io::Error::last_os_error();
+            libc::close(queue);
+            if err.raw_os_error() == Some(libc::ESRCH) {
+                error!("broker exited before sandboxed process started");
+                libc::_exit(1);
+            }
+            return Err(err);
+        }
+        thread::Builder::new().name("sandbox-parent-watch".to_owned()).spawn(move || {
+            let mut event: libc::kevent = mem::zeroed();
+            loop {
+                match libc::kevent(queue, ptr::null(), 0, &mut event, 1, ptr::null()) {
+                    1 => break,
+                    -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                    -1 => {
+                        error!("failed to watch for broker exit: {}", io::Error::last_os_error());
+                        return;
+                    },
+                    _ => continue,
+                }
+            }
+            libc::_exit(1);
+        })?;
+    }
+    Ok(())
 }
 
 pub(in platform) const MAX_MESSAGE_SIZE: usize = 16384;
@@ -56,8 +155,10 @@ pub(in platform) const MAX_MESSAGE_SIZE: usize = 16384;
 #[derive(Serialize, Deserialize)]
 pub(in platform) enum BrokerMessage {
     PolicySpec(Policy), // FIXME: support policies longer than MAX_MESSAGE_SIZE
+    User(String),
 }
 
 #[derive(Serialize, Deserialize)]
 pub(in platform) enum TargetMessage {
+    User(String),
 }
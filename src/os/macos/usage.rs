@@ -0,0 +1,97 @@
+use ::ResourceUsage;
+
+use std::{io, mem};
+use std::time::Duration;
+
+use libc::{self, c_int, c_void};
+
+/// Reaps an exited child (or any child, if `process_id` is -1), along with its resource usage.
+/// Returns `None` if `block` is false and no child has exited yet.
+pub(in platform) fn reap(process_id: i32, block: bool) -> io::Result<Option<(i32, c_int, ResourceUsage)>> {
+    unsafe {
+        // Wait without reaping first, since a zombie's disk I/O counters are lost once it's reaped
+        let mut info: libc::siginfo_t = mem::zeroed();
+        let (idtype, id) = if process_id == -1 {
+            (libc::P_ALL, 0)
+        } else {
+            (libc::P_PID, process_id as libc::id_t)
+        };
+        let mut options = libc::WEXITED | libc::WNOWAIT;
+        if !block {
+            options |= libc::WNOHANG;
+        }
+        loop {
+            if libc::waitid(idtype, id, &mut info, options) == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            break;
+        }
+        if info.si_pid == 0 {
+            return Ok(None);
+        }
+        let process_id = info.si_pid;
+
+        let mut disk_io: RusageInfoV2 = mem::zeroed();
+        let disk_io = if proc_pid_rusage(process_id, RUSAGE_INFO_V2, &mut disk_io as *mut RusageInfoV2 as *mut c_void) == 0 {
+            Some(disk_io)
+        } else {
+            warn!("failed to get disk I/O counters for process {}: {}", process_id, io::Error::last_os_error());
+            None
+        };
+
+        let mut status: c_int = 0;
+        let mut rusage: libc::rusage = mem::zeroed();
+        try_libc!(pid: libc::wait4(process_id, &mut status, 0, &mut rusage), "wait4 failed: {}");
+
+        Ok(Some((process_id, status, ResourceUsage {
+            user_time: timeval_duration(&rusage.ru_utime),
+            system_time: timeval_duration(&rusage.ru_stime),
+            // Unlike on Linux, this is already in bytes
+            max_rss: rusage.ru_maxrss as u64,
+            minor_page_faults: rusage.ru_minflt as u64,
+            major_page_faults: rusage.ru_majflt as u64,
+            voluntary_context_switches: rusage.ru_nvcsw as u64,
+            involuntary_context_switches: rusage.ru_nivcsw as u64,
+            read_bytes: disk_io.as_ref().map(|x| x.ri_diskio_bytesread),
+            written_bytes: disk_io.as_ref().map(|x| x.ri_diskio_byteswritten),
+        })))
+    }
+}
+
+fn timeval_duration(time: &libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
+
+const RUSAGE_INFO_V2: c_int = 2;
+
+// From <sys/resource.h>
+#[repr(C)]
+struct RusageInfoV2 {
+    ri_uuid: [u8; 16],
+    ri_user_time: u64,
+    ri_system_time: u64,
+    ri_pkg_idle_wkups: u64,
+    ri_interrupt_wkups: u64,
+    ri_pageins: u64,
+    ri_wired_size: u64,
+    ri_resident_size: u64,
+    ri_phys_footprint: u64,
+    ri_proc_start_abstime: u64,
+    ri_proc_exit_abstime: u64,
+    ri_child_user_time: u64,
+    ri_child_system_time: u64,
+    ri_child_pkg_idle_wkups: u64,
+    ri_child_interrupt_wkups: u64,
+    ri_child_pageins: u64,
+    ri_child_elapsed_abstime: u64,
+    ri_diskio_bytesread: u64,
+    ri_diskio_byteswritten: u64,
+}
+
+extern "C" {
+    fn proc_pid_rusage(pid: c_int, flavor: c_int, buffer: *mut c_void) -> c_int;
+}
@@ -0,0 +1,240 @@
+use ::ViolationReport;
+
+use std::{io, mem, ptr, slice};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::prelude::*;
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+use libc::{self, c_int, c_void};
+
+/// The record written by the `SIGSYS` handler. It has a fixed size so it can be written with a
+/// single `write`, and read back by the broker without any framing.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawViolationRecord {
+    // -1 if the syscall could not be determined
+    syscall: i64,
+    arguments: [u64; 6],
+    instruction_pointer: u64,
+}
+
+static VIOLATION_FD: AtomicIsize = AtomicIsize::new(-1);
+
+/// Installs a handler that reports the first `SIGSYS` the process receives over `fd`, then lets
+/// it kill the process as it would have without the handler.
+pub(in platform) fn install_trap_handler(fd: c_int) -> io::Result<()> {
+    // The pipe had to survive exec into the target, but programs it executes must not inherit it
+    unsafe { try_libc!(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC)); }
+    VIOLATION_FD.store(fd as isize, Ordering::SeqCst);
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_sigsys as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESETHAND;
+        try_libc!(libc::sigaction(libc::SIGSYS, &action, ptr::null_mut()));
+    }
+    Ok(())
+}
+
+// WARNING: No allocation is allowed in this function
+extern "C" fn handle_sigsys(_signal: c_int, _info: *mut libc::siginfo_t, context: *mut c_void) {
+    unsafe {
+        let record = capture_record(context as *const UContext);
+        let fd = VIOLATION_FD.swap(-1, Ordering::SeqCst) as c_int;
+        if fd >= 0 {
+            libc::write(fd, &record as *const RawViolationRecord as *const c_void, mem::size_of::<RawViolationRecord>());
+        }
+        // SA_RESETHAND restored the default action, which terminates the process
+        libc::raise(libc::SIGSYS);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn capture_record(context: *const UContext) -> RawViolationRecord {
+    let state = &(*(*context).uc_mcontext).ss;
+    RawViolationRecord {
+        syscall: syscall_before(state.rip),
+        arguments: [state.rdi, state.rsi, state.rdx, state.r10, state.r8, state.r9],
+        instruction_pointer: state.rip,
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn capture_record(_context: *const UContext) -> RawViolationRecord {
+    RawViolationRecord {
+        syscall: -1,
+        arguments: [0; 6],
+        instruction_pointer: 0,
+    }
+}
+
+// The sandbox signals the process as the denied syscall returns, by which point %rax holds its
+// result rather than its number. The libsystem_kernel stubs always load the number immediately
+// beforehand (`mov $number, %eax; mov %rcx, %r10; syscall`), so recover it from the stub's code.
+#[cfg(target_arch = "x86_64")]
+unsafe fn syscall_before(rip: u64) -> i64 {
+    const STUB_LEN: usize = 10;
+    // The smallest page size, so a boundary is never missed
+    const PAGE_SIZE: u64 = 4096;
+    if rip < STUB_LEN as u64 {
+        return -1;
+    }
+    // The `syscall` instruction just ran, so its page is mapped, but the page before it may not
+    // be; reading it would fault inside the handler
+    if (rip - STUB_LEN as u64) / PAGE_SIZE != (rip - 1) / PAGE_SIZE {
+        return -1;
+    }
+    let stub = slice::from_raw_parts((rip as usize - STUB_LEN) as *const u8, STUB_LEN);
+    if stub[0] != 0xb8 || stub[5..] != [0x49, 0x89, 0xca, 0x0f, 0x05] {
+        return -1;
+    }
+    let number = (stub[1] as u32) | ((stub[2] as u32) << 8) | ((stub[3] as u32) << 16) | ((stub[4] as u32) << 24);
+    // The top byte selects the syscall class; only BSD syscalls (class 2) can be denied
+    if number >> 24 != 2 {
+        return -1;
+    }
+    (number & 0xffffff) as i64
+}
+
+/// Reads the record written by the handler, if the child was killed by it. Must only be called
+/// after the child has exited.
+pub(in platform) fn read_report(rx: &mut File) -> Option<ViolationReport> {
+    let mut buffer = [0u8; 64];
+    assert_eq!(buffer.len(), mem::size_of::<RawViolationRecord>());
+    // Descendants may have inherited the pipe, so don't wait for it to close
+    unsafe { libc::fcntl(rx.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK); }
+    if rx.read_exact(&mut buffer).is_err() {
+        return None;
+    }
+    let record: RawViolationRecord = unsafe { ptr::read_unaligned(buffer.as_ptr() as *const RawViolationRecord) };
+    let syscall = if record.syscall >= 0 { Some(record.syscall as u64) } else { None };
+    Some(ViolationReport {
+        syscall,
+        syscall_name: syscall.and_then(syscall_name),
+        arguments: record.arguments,
+        instruction_pointer: record.instruction_pointer,
+    })
+}
+
+// Names of commonly denied BSD syscalls, from xnu's bsd/kern/syscalls.master
+fn syscall_name(number: u64) -> Option<&'static str> {
+    Some(match number {
+        1 => "exit",
+        2 => "fork",
+        3 => "read",
+        4 => "write",
+        5 => "open",
+        6 => "close",
+        7 => "wait4",
+        9 => "link",
+        10 => "unlink",
+        12 => "chdir",
+        13 => "fchdir",
+        14 => "mknod",
+        15 => "chmod",
+        16 => "chown",
+        20 => "getpid",
+        23 => "setuid",
+        24 => "getuid",
+        26 => "ptrace",
+        27 => "recvmsg",
+        28 => "sendmsg",
+        29 => "recvfrom",
+        30 => "accept",
+        33 => "access",
+        37 => "kill",
+        41 => "dup",
+        42 => "pipe",
+        54 => "ioctl",
+        57 => "symlink",
+        58 => "readlink",
+        59 => "execve",
+        61 => "chroot",
+        73 => "munmap",
+        74 => "mprotect",
+        92 => "fcntl",
+        97 => "socket",
+        98 => "connect",
+        104 => "bind",
+        105 => "setsockopt",
+        106 => "listen",
+        128 => "rename",
+        133 => "sendto",
+        135 => "socketpair",
+        136 => "mkdir",
+        137 => "rmdir",
+        188 => "stat",
+        189 => "fstat",
+        190 => "lstat",
+        197 => "mmap",
+        199 => "lseek",
+        202 => "sysctl",
+        338 => "stat64",
+        339 => "fstat64",
+        340 => "lstat64",
+        344 => "getdirentries64",
+        362 => "kqueue",
+        363 => "kevent",
+        396 => "read_nocancel",
+        397 => "write_nocancel",
+        398 => "open_nocancel",
+        463 => "openat",
+        464 => "openat_nocancel",
+        _ => return None,
+    })
+}
+
+// From <sys/_types/_ucontext.h> and <mach/i386/_structs.h>, as libc doesn't define them
+#[repr(C)]
+#[allow(dead_code)]
+struct UContext {
+    uc_onstack: c_int,
+    uc_sigmask: u32,
+    uc_stack: libc::stack_t,
+    uc_link: *mut UContext,
+    uc_mcsize: usize,
+    uc_mcontext: *const MContext64,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct MContext64 {
+    es: ExceptionState64,
+    ss: ThreadState64,
+    // Followed by the floating point state, which we don't need
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ExceptionState64 {
+    trapno: u16,
+    cpu: u16,
+    err: u32,
+    faultvaddr: u64,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ThreadState64 {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rdi: u64,
+    rsi: u64,
+    rbp: u64,
+    rsp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rflags: u64,
+    cs: u64,
+    fs: u64,
+    gs: u64,
+}
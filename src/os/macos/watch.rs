@@ -0,0 +1,111 @@
+use super::command::Child;
+
+use std::{io, mem, ptr};
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::Async;
+use futures::task::AtomicTask;
+use mio::{self, Evented, Ready, PollOpt, Token};
+use mio::unix::EventedFd;
+use tokio::reactor::PollEvented;
+use libc::{self, c_int};
+
+/// Wakes the task polling it when a watched child exits or reaches its deadline, through a kqueue
+/// registered with the broker's reactor.
+/// 
+/// Targets forked by a zygote can only be reaped once the zygote reports their exit, which may
+/// arrive after the kqueue's notification, so the zygote wakes the task as well.
+pub struct ExitWatcher {
+    kqueue: PollEvented<Kqueue>,
+    task: Arc<AtomicTask>,
+}
+
+struct Kqueue(c_int);
+
+impl ExitWatcher {
+    pub(crate) fn new(broker: &::BrokerServices) -> io::Result<Self> {
+        let kqueue = Kqueue(unsafe { try_libc!(fd: libc::kqueue()) });
+        Ok(ExitWatcher {
+            kqueue: PollEvented::new_with_handle(kqueue, broker.inner.event_loop.handle())?,
+            task: Arc::new(AtomicTask::new()),
+        })
+    }
+
+    pub(crate) fn watch(&mut self, child: &Child, deadline: Option<Instant>) -> io::Result<()> {
+        if let Some(zygote) = child.zygote() {
+            zygote.notify_exits(&self.task);
+        }
+        let process_id = child.id() as libc::uintptr_t;
+        let mut changes = vec![kevent(process_id, libc::EVFILT_PROC, libc::NOTE_EXIT, 0)];
+        if let Some(deadline) = deadline {
+            // Round up, so the deadline has passed once the timer fires
+            let now = Instant::now();
+            let remaining = if deadline > now { deadline - now } else { Default::default() };
+            let millis = remaining.as_secs() * 1000 + ((remaining.subsec_nanos() + 999_999) / 1_000_000) as u64;
+            changes.push(kevent(process_id, libc::EVFILT_TIMER, 0, millis as libc::intptr_t));
+        }
+        for change in changes.iter() {
+            if unsafe { libc::kevent(self.kqueue.get_ref().0, change, 1, ptr::null_mut(), 0, ptr::null()) } == -1 {
+                let err = io::Error::last_os_error();
+                // The child has exited and been reaped already, which the next poll will notice
+                if err.raw_os_error() != Some(libc::ESRCH) {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards pending notifications, and registers the current task to be woken by the next one.
+    /// Callers must check every child afterwards, since any of them may have caused a notification.
+    pub(crate) fn poll(&mut self) -> io::Result<()> {
+        self.task.register();
+        if let Async::Ready(_) = self.kqueue.poll_read_ready(Ready::readable())? {
+            let timeout = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            let mut events: [libc::kevent; 16] = unsafe { mem::zeroed() };
+            loop {
+                let count = unsafe { libc::kevent(self.kqueue.get_ref().0, ptr::null(), 0, events.as_mut_ptr(), events.len() as c_int, &timeout) };
+                if count == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if (count as usize) < events.len() {
+                    break;
+                }
+            }
+            self.kqueue.clear_read_ready(Ready::readable())?;
+        }
+        Ok(())
+    }
+}
+
+fn kevent(ident: libc::uintptr_t, filter: i16, fflags: u32, data: libc::intptr_t) -> libc::kevent {
+    libc::kevent {
+        ident,
+        filter,
+        flags: libc::EV_ADD | libc::EV_ONESHOT,
+        fflags,
+        data,
+        udata: ptr::null_mut(),
+    }
+}
+
+impl Evented for Kqueue {
+    fn register(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+impl Drop for Kqueue {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0); }
+    }
+}
@@ -0,0 +1,527 @@
+use ::ResourceUsage;
+use ::command::{Command, Resolved};
+use super::{ZYGOTE_ENV_VAR};
+use super::command::{Child, do_spawn, check_not_setugid};
+use super::usage::reap;
+
+use std::{io, mem, ptr, thread};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{Read};
+use std::os::unix::prelude::*;
+use std::path::PathBuf;
+use std::process::{Command as StdCommand, ExitStatus};
+use std::sync::{Arc, Weak, Mutex, Condvar};
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+use futures::task::AtomicTask;
+use ipc::{ChildRawMessageChannel};
+use json;
+use libc::{self, c_int, c_void};
+
+/// A sandbox-aware process that forks new targets on request.
+///
+/// The zygote is launched like any other target, but `init` enters `run` instead of returning. Each
+/// fork request carries the target end of a fresh IPC channel, which the forked process returns
+/// from `init` as a target. The zygote reaps its children and reports their exit statuses, since
+/// they are not children of the broker.
+pub struct Zygote {
+    process_id: i32,
+    shared: Arc<ZygoteShared>,
+}
+
+pub(in platform) struct ZygoteShared {
+    control: File,
+    // Held while sending a request and waiting for its reply, so replies can't be mismatched
+    request_lock: Mutex<()>,
+    state: Mutex<ZygoteState>,
+    changed: Condvar,
+    // Tasks of `ExitWatcher`s watching forked targets, which are woken when an exit is reported.
+    // The kernel's exit notification can arrive before the zygote reports the exit.
+    exit_tasks: Mutex<Vec<Weak<AtomicTask>>>,
+}
+
+struct ZygoteState {
+    replies: VecDeque<ZygoteReply>,
+    exits: HashMap<i32, (c_int, Option<ResourceUsage>)>,
+    closed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ZygoteRequest {
+    // The channel's file descriptor is attached to the message. `channel_fd` is the number it has
+    // in the broker, which the serialized channel refers to, so the forked target moves it there.
+    // The fork is refused if that number is already in use in the zygote.
+    Fork {
+        channel: String,
+        channel_fd: c_int,
+    },
+    Resume {
+        pid: i32,
+    },
+    Kill {
+        pid: i32,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+enum ZygoteReply {
+    Forked {
+        pid: i32,
+    },
+    ForkFailed {
+        errno: i32,
+    },
+    Resumed {
+        errno: i32,
+    },
+    Exited {
+        pid: i32,
+        status: c_int,
+        usage: Option<ResourceUsage>,
+    },
+}
+
+impl Zygote {
+    pub fn spawn(_services: &mut ::BrokerServices, command: &mut Command, resolved: Resolved) -> io::Result<Self> {
+        check_not_setugid(&command.program)?;
+
+        let mut std_command = StdCommand::new(&command.program);
+        std_command.env_clear();
+        std_command.args(&command.arguments);
+        if let Some(current_dir) = command.current_dir.as_ref() {
+            std_command.current_dir(current_dir);
+        }
+        std_command.envs(resolved.envs);
+
+        let (control, zygote_control) = unix_socket_pair()?;
+        unsafe { try_libc!(libc::fcntl(control.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC)); }
+        std_command.env(ZYGOTE_ENV_VAR, zygote_control.as_raw_fd().to_string());
+
+        let (process_id, mut error_rx) = do_spawn(&mut std_command, Some(zygote_control.as_raw_fd()), false, None)?;
+        mem::drop(zygote_control);
+
+        // Let the zygote past the pause before exec; it has no policy to wait for
+        let mut status: c_int = 0;
+        unsafe { try_libc!(pid: libc::waitpid(process_id, &mut status, libc::WUNTRACED)); }
+        if !unsafe { libc::WIFSTOPPED(status) } {
+            let mut bytes = [0u8; 4];
+            if error_rx.read_exact(&mut bytes).is_ok() {
+                let errno = (((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | ((bytes[3] as u32) << 0)) as i32;
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            return Err(io::Error::new(io::ErrorKind::Other, format!("zygote process exited before pausing for exec with status: {}", ExitStatus::from_raw(status))));
+        }
+        unsafe { try_libc!(libc::kill(process_id, libc::SIGCONT)); }
+
+        let shared = Arc::new(ZygoteShared {
+            control: control.try_clone()?,
+            request_lock: Mutex::new(()),
+            state: Mutex::new(ZygoteState {
+                replies: VecDeque::new(),
+                exits: HashMap::new(),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            exit_tasks: Mutex::new(Vec::new()),
+        });
+        let reader_shared = shared.clone();
+        thread::spawn(move || reader_shared.read_replies(control));
+
+        Ok(Zygote {
+            process_id,
+            shared,
+        })
+    }
+
+    pub fn fork(&mut self, services: &mut ::BrokerServices, policy: &::Policy, rule_paths: Vec<PathBuf>) -> io::Result<Child> {
+        let shared = self.shared.clone();
+        Child::fork_from_zygote(services, policy, rule_paths, self.shared.clone(), move |child_channel| {
+            let channel_fd = child_channel.as_raw_fd();
+            let request = ZygoteRequest::Fork {
+                channel: json::to_string(child_channel).unwrap(),
+                channel_fd,
+            };
+            match shared.request(&request, Some(channel_fd))? {
+                ZygoteReply::Forked { pid } => Ok(pid),
+                ZygoteReply::ForkFailed { errno } => Err(io::Error::from_raw_os_error(errno)),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected reply from zygote")),
+            }
+        })
+    }
+}
+
+impl Drop for Zygote {
+    fn drop(&mut self) {
+        // Closing the control socket makes the zygote kill its targets and exit
+        unsafe {
+            libc::shutdown(self.shared.control.as_raw_fd(), libc::SHUT_RDWR);
+            let mut status: c_int = 0;
+            if libc::waitpid(self.process_id, &mut status, 0) == -1 {
+                error!("failed to wait for zygote process: {}", io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+impl ZygoteShared {
+    fn request(&self, request: &ZygoteRequest, attached_fd: Option<c_int>) -> io::Result<ZygoteReply> {
+        let _guard = self.request_lock.lock().unwrap();
+        send_frame(self.control.as_raw_fd(), json::to_string(request).unwrap().as_bytes(), attached_fd)?;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(reply) = state.replies.pop_front() {
+                return Ok(reply);
+            }
+            if state.closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "zygote process has exited"));
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    pub(in platform) fn resume(&self, pid: i32) -> io::Result<()> {
+        match self.request(&ZygoteRequest::Resume { pid }, None)? {
+            ZygoteReply::Resumed { errno: 0 } => Ok(()),
+            ZygoteReply::Resumed { errno } => Err(io::Error::from_raw_os_error(errno)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected reply from zygote")),
+        }
+    }
+
+    pub(in platform) fn kill(&self, pid: i32) -> io::Result<()> {
+        // The zygote only kills processes it hasn't reaped, so the ID can't have been reused
+        let _guard = self.request_lock.lock().unwrap();
+        send_frame(self.control.as_raw_fd(), json::to_string(&ZygoteRequest::Kill { pid }).unwrap().as_bytes(), None)
+    }
+
+    pub(in platform) fn wait(&self, pid: i32, block: bool) -> io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some((status, usage)) = state.exits.remove(&pid) {
+                return Ok(Some((ExitStatus::from_raw(status), usage)));
+            }
+            if state.closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "zygote process has exited"));
+            }
+            if !block {
+                return Ok(None);
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Wakes `task` whenever the zygote reports that a target exited, or exits itself.
+    pub(in platform) fn notify_exits(&self, task: &Arc<AtomicTask>) {
+        let mut tasks = self.exit_tasks.lock().unwrap();
+        tasks.retain(|registered| registered.upgrade().is_some());
+        if !tasks.iter().any(|registered| registered.upgrade().map(|registered| Arc::ptr_eq(&registered, task)).unwrap_or(false)) {
+            tasks.push(Arc::downgrade(task));
+        }
+    }
+
+    fn wake_exit_tasks(&self) {
+        for task in self.exit_tasks.lock().unwrap().iter().filter_map(|task| task.upgrade()) {
+            task.notify();
+        }
+    }
+
+    fn read_replies(&self, control: File) {
+        loop {
+            let reply = match recv_frame(control.as_raw_fd()) {
+                Ok(Some((frame, _))) => match json::from_slice::<ZygoteReply>(&frame) {
+                    Ok(reply) => reply,
+                    Err(err) => {
+                        error!("invalid message from zygote: {}", err);
+                        break;
+                    },
+                },
+                Ok(None) => break,
+                Err(err) => {
+                    error!("error reading from zygote: {}", err);
+                    break;
+                },
+            };
+            let exited = {
+                let mut state = self.state.lock().unwrap();
+                let exited = match reply {
+                    ZygoteReply::Exited { pid, status, usage } => {
+                        state.exits.insert(pid, (status, usage));
+                        true
+                    },
+                    reply => {
+                        state.replies.push_back(reply);
+                        false
+                    },
+                };
+                self.changed.notify_all();
+                exited
+            };
+            if exited {
+                self.wake_exit_tasks();
+            }
+        }
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+        self.wake_exit_tasks();
+    }
+}
+
+static SIGCHLD_PIPE: AtomicIsize = AtomicIsize::new(-1);
+
+extern "C" fn handle_sigchld(_signal: c_int) {
+    let fd = SIGCHLD_PIPE.load(Ordering::SeqCst) as c_int;
+    if fd >= 0 {
+        let byte = 0u8;
+        unsafe { libc::write(fd, &byte as *const u8 as *const c_void, 1); }
+    }
+}
+
+/// Runs the zygote's request loop. Only returns in forked targets, with their IPC channel.
+pub(in platform) fn run(control_fd: c_int) -> io::Result<ChildRawMessageChannel> {
+    unsafe {
+        let control = control_fd;
+        let mut sigchld_pipe: [c_int; 2] = [0; 2];
+        try_libc!(libc::pipe(sigchld_pipe.as_mut_ptr()));
+        for &fd in sigchld_pipe.iter() {
+            try_libc!(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC));
+        }
+        try_libc!(libc::fcntl(sigchld_pipe[1], libc::F_SETFL, libc::O_NONBLOCK));
+        SIGCHLD_PIPE.store(sigchld_pipe[1] as isize, Ordering::SeqCst);
+
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_sigchld as usize;
+        action.sa_flags = libc::SA_NOCLDSTOP | libc::SA_RESTART;
+        try_libc!(libc::sigaction(libc::SIGCHLD, &action, ptr::null_mut()));
+
+        let mut children = HashSet::new();
+        loop {
+            let mut fds = [
+                libc::pollfd { fd: control, events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: sigchld_pipe[0], events: libc::POLLIN, revents: 0 },
+            ];
+            if libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            if fds[1].revents != 0 {
+                let mut buffer = [0u8; 64];
+                libc::read(sigchld_pipe[0], buffer.as_mut_ptr() as *mut c_void, buffer.len());
+                // Fails with ECHILD once there are no children left at all
+                while let Ok(Some((pid, status, usage))) = reap(-1, false) {
+                    children.remove(&pid);
+                    reply(control, &ZygoteReply::Exited { pid, status, usage: Some(usage) })?;
+                }
+            }
+
+            if fds[0].revents == 0 {
+                continue;
+            }
+            let (frame, attached_fd) = match recv_frame(control)? {
+                Some(frame) => frame,
+                None => {
+                    // The broker has gone away, so nobody can wait for our targets anymore
+                    debug!("zygote control channel closed, killing {} targets", children.len());
+                    for &pid in children.iter() {
+                        libc::kill(pid, libc::SIGKILL);
+                    }
+                    libc::_exit(0);
+                },
+            };
+            let request = json::from_slice::<ZygoteRequest>(&frame)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            match request {
+                ZygoteRequest::Fork { channel, channel_fd } => {
+                    let received_fd = attached_fd
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "fork request did not include an IPC channel"))?;
+                    // Moving the channel must not close anything the target inherits. The control
+                    // socket and the SIGCHLD pipe are closed in the target anyway.
+                    let in_use = channel_fd != received_fd
+                        && channel_fd != control
+                        && !sigchld_pipe.contains(&channel_fd)
+                        && libc::fcntl(channel_fd, libc::F_GETFD) != -1;
+                    if in_use {
+                        libc::close(received_fd);
+                        reply(control, &ZygoteReply::ForkFailed { errno: libc::EBUSY })?;
+                        continue;
+                    }
+                    match libc::fork() {
+                        -1 => {
+                            let errno = io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL);
+                            libc::close(received_fd);
+                            reply(control, &ZygoteReply::ForkFailed { errno })?;
+                        },
+                        0 => {
+                            // Become an ordinary target, with the channel at the descriptor number
+                            // the serialized channel refers to, which was checked to be free
+                            SIGCHLD_PIPE.store(-1, Ordering::SeqCst);
+                            action.sa_sigaction = libc::SIG_DFL;
+                            try_libc!(libc::sigaction(libc::SIGCHLD, &action, ptr::null_mut()));
+                            libc::close(sigchld_pipe[0]);
+                            libc::close(sigchld_pipe[1]);
+                            libc::close(control);
+                            if received_fd != channel_fd {
+                                try_libc!(fd: libc::dup2(received_fd, channel_fd));
+                                libc::close(received_fd);
+                            }
+                            // Wait for the broker to resume us, like a freshly spawned target
+                            libc::raise(libc::SIGSTOP);
+                            return json::from_str(&channel)
+                                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+                        },
+                        pid => {
+                            libc::close(received_fd);
+                            children.insert(pid);
+                            reply(control, &ZygoteReply::Forked { pid })?;
+                        },
+                    }
+                },
+                ZygoteRequest::Resume { pid } => {
+                    let errno = if !children.contains(&pid) {
+                        libc::ESRCH
+                    } else {
+                        let mut status: c_int = 0;
+                        if libc::waitpid(pid, &mut status, libc::WUNTRACED) == -1 {
+                            io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL)
+                        } else if !libc::WIFSTOPPED(status) {
+                            children.remove(&pid);
+                            // Reaped without collecting its resource usage, but it never got to run
+                            reply(control, &ZygoteReply::Exited { pid, status, usage: None })?;
+                            libc::ESRCH
+                        } else if libc::kill(pid, libc::SIGCONT) == -1 {
+                            io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL)
+                        } else {
+                            0
+                        }
+                    };
+                    reply(control, &ZygoteReply::Resumed { errno })?;
+                },
+                ZygoteRequest::Kill { pid } => {
+                    if children.contains(&pid) {
+                        libc::kill(pid, libc::SIGKILL);
+                    }
+                },
+            }
+        }
+    }
+}
+
+fn reply(control: c_int, reply: &ZygoteReply) -> io::Result<()> {
+    send_frame(control, json::to_string(reply).unwrap().as_bytes(), None)
+}
+
+// Frames are a 4 byte big-endian length followed by the message. A file descriptor may be attached
+// to the length.
+fn send_frame(fd: c_int, message: &[u8], attached_fd: Option<c_int>) -> io::Result<()> {
+    let len = message.len() as u32;
+    let mut header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: header.as_mut_ptr() as *mut c_void,
+            iov_len: header.len(),
+        };
+        let mut control_buffer = [0u8; 64];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if let Some(attached_fd) = attached_fd {
+            msg.msg_control = control_buffer.as_mut_ptr() as *mut c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<c_int>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as u32) as _;
+            ptr::write(libc::CMSG_DATA(cmsg) as *mut c_int, attached_fd);
+        }
+        let count = libc::sendmsg(fd, &msg, 0);
+        if count == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if count as usize != header.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "short write of frame header"));
+        }
+        let mut written = 0;
+        while written < message.len() {
+            let count = libc::write(fd, message[written..].as_ptr() as *const c_void, message.len() - written);
+            if count == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            written += count as usize;
+        }
+    }
+    Ok(())
+}
+
+fn recv_frame(fd: c_int) -> io::Result<Option<(Vec<u8>, Option<c_int>)>> {
+    let mut header = [0u8; 4];
+    let mut attached_fd = None;
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: header.as_mut_ptr() as *mut c_void,
+            iov_len: header.len(),
+        };
+        let mut control_buffer = [0u8; 64];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control_buffer.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = control_buffer.len() as _;
+        let count = loop {
+            let count = libc::recvmsg(fd, &mut msg, 0);
+            if count == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            break count as usize;
+        };
+        if count == 0 {
+            return Ok(None);
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if !cmsg.is_null() && (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+            attached_fd = Some(ptr::read(libc::CMSG_DATA(cmsg) as *const c_int));
+        }
+        read_exact_fd(fd, &mut header[count..])?;
+    }
+    let len = (((header[0] as u32) << 24) | ((header[1] as u32) << 16) | ((header[2] as u32) << 8) | ((header[3] as u32) << 0)) as usize;
+    let mut message = vec![0u8; len];
+    read_exact_fd(fd, &mut message)?;
+    Ok(Some((message, attached_fd)))
+}
+
+fn read_exact_fd(fd: c_int, mut buffer: &mut [u8]) -> io::Result<()> {
+    while !buffer.is_empty() {
+        let count = unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()) };
+        if count == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if count == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "zygote control channel closed mid-frame"));
+        }
+        let tmp = buffer;
+        buffer = &mut tmp[(count as usize)..];
+    }
+    Ok(())
+}
+
+fn unix_socket_pair() -> io::Result<(File, File)> {
+    unsafe {
+        let mut fds: [c_int; 2] = [0; 2];
+        try_libc!(libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()));
+        Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])))
+    }
+}
@@ -1,14 +1,25 @@
-use ::{Command, PolicyPreset};
+use ::{Command, PolicyPreset, ResourceUsage, ViolationAction, ViolationReport};
+use command::{Resolved, KillMechanism};
+use policy::{PathRule};
 
-use std::{io};
+use std::{io, cmp, mem, thread};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::process::ExitStatus;
 use std::os::windows::process::ExitStatusExt;
 
 use winapi::shared::winerror::{WAIT_TIMEOUT};
-use winapi::um::winbase::{INFINITE, WAIT_OBJECT_0};
+use winapi::um::winbase::{INFINITE, WAIT_OBJECT_0, GetProcessIoCounters};
 use winapi::um::synchapi::{WaitForSingleObject};
-use winapi::um::processthreadsapi::{GetExitCodeProcess, TerminateProcess};
+use winapi::shared::minwindef::{FALSE, FILETIME};
+use winapi::um::handleapi::{CloseHandle};
+use winapi::um::processthreadsapi::{GetExitCodeProcess, GetProcessTimes, OpenProcess, TerminateProcess};
+use winapi::um::psapi::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+use winapi::um::winnt::{IO_COUNTERS, PROCESS_TERMINATE, SYNCHRONIZE};
 use crsio2::{self, TokenLevel};
+use futures::Async;
+use futures::task::AtomicTask;
 
 macro_rules! try_crsio2 {
     ($x:expr) => {
@@ -21,6 +32,9 @@ macro_rules! try_crsio2 {
     }
 }
 
+/// Environment variables used by the sandbox itself, which must never be set by callers.
+pub(crate) const RESERVED_ENV_VARS: &[&str] = &[];
+
 pub struct Child {
     inner: crsio2::TargetProcess,
 }
@@ -31,6 +45,9 @@ pub struct Policy {
 
 pub struct PolicyBuilder {
     inner: crsio2::Policy,
+    learning_mode: bool,
+    violation_action: ViolationAction,
+    unsupported_preset: Option<&'static str>,
 }
 
 pub struct BrokerServices {
@@ -62,7 +79,19 @@ pub fn init() -> io::Result<Services> {
 }
 
 impl Child {
-    pub fn spawn(services: &mut ::BrokerServices, command: &mut Command) -> io::Result<Self> {
+    pub fn spawn(services: &mut ::BrokerServices, command: &mut Command, _resolved: Resolved) -> io::Result<Self> {
+        if command.lockdown_at_exec {
+            // FIXME: crsio2 always starts targets with the initial token and expects them to lower it
+            return Err(io::Error::new(io::ErrorKind::Other, "lockdown at exec is not supported on Windows"));
+        }
+        if command.new_process_group {
+            // FIXME: a job object would give us the same guarantee
+            return Err(io::Error::new(io::ErrorKind::Other, "process groups are not supported on Windows"));
+        }
+        if command.scratch_dir.is_some() {
+            // FIXME: needs environment variables to be passed to targets
+            return Err(io::Error::new(io::ErrorKind::Other, "scratch directories are not supported on Windows"));
+        }
         let inner = try_crsio2!(services.inner.inner.spawn_target(
             &command.program,
             "", // FIXME
@@ -117,24 +146,262 @@ impl Child {
             Ok(())
         }
     }
+
+    pub fn resource_usage(&self) -> Option<ResourceUsage> {
+        // The handle stays open until the child is dropped, so the counters can be read any time
+        // after it exits
+        unsafe {
+            let handle = self.inner.get_process_handle();
+            if WaitForSingleObject(handle, 0) != WAIT_OBJECT_0 {
+                return None;
+            }
+            let mut creation_time: FILETIME = mem::zeroed();
+            let mut exit_time: FILETIME = mem::zeroed();
+            let mut kernel_time: FILETIME = mem::zeroed();
+            let mut user_time: FILETIME = mem::zeroed();
+            if GetProcessTimes(handle, &mut creation_time, &mut exit_time, &mut kernel_time, &mut user_time) == FALSE {
+                warn!("failed to get CPU times of sandboxed process: {}", io::Error::last_os_error());
+                return None;
+            }
+            let mut memory: PROCESS_MEMORY_COUNTERS = mem::zeroed();
+            if GetProcessMemoryInfo(handle, &mut memory, mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32) == FALSE {
+                warn!("failed to get memory usage of sandboxed process: {}", io::Error::last_os_error());
+                return None;
+            }
+            let mut io_counters: IO_COUNTERS = mem::zeroed();
+            let io_counters = if GetProcessIoCounters(handle, &mut io_counters) != FALSE {
+                Some(io_counters)
+            } else {
+                None
+            };
+            Some(ResourceUsage {
+                user_time: filetime_duration(&user_time),
+                system_time: filetime_duration(&kernel_time),
+                max_rss: memory.PeakWorkingSetSize as u64,
+                minor_page_faults: memory.PageFaultCount as u64,
+                read_bytes: io_counters.as_ref().map(|x| x.ReadTransferCount),
+                written_bytes: io_counters.as_ref().map(|x| x.WriteTransferCount),
+                ..Default::default()
+            })
+        }
+    }
+
+    pub fn kill_tree(&mut self) -> io::Result<KillMechanism> {
+        self.kill()?;
+        Ok(KillMechanism::ProcessOnly)
+    }
+
+    pub fn send_message(&mut self, _message: String) -> io::Result<()> {
+        // FIXME: crsio2 doesn't give us an IPC channel to the target
+        Err(io::Error::new(io::ErrorKind::Other, "messaging is not supported on Windows"))
+    }
+
+    pub fn recv_message(&mut self) -> io::Result<Option<String>> {
+        Err(io::Error::new(io::ErrorKind::Other, "messaging is not supported on Windows"))
+    }
+
+    pub(crate) fn poll_message(&mut self) -> io::Result<Async<Option<String>>> {
+        Err(io::Error::new(io::ErrorKind::Other, "messaging is not supported on Windows"))
+    }
+
+    pub fn violation_report(&self) -> Option<ViolationReport> {
+        // Policies that trap violations are rejected when building
+        None
+    }
+
+    pub fn learned_policy(&self) -> io::Result<::LearnedPolicy> {
+        Err(io::Error::new(io::ErrorKind::Other, "learning mode is not supported on Windows"))
+    }
+}
+
+impl TargetServices {
+    pub fn send_message(&mut self, _message: String) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "messaging is not supported on Windows"))
+    }
+
+    pub fn recv_message(&mut self) -> io::Result<Option<String>> {
+        Err(io::Error::new(io::ErrorKind::Other, "messaging is not supported on Windows"))
+    }
+}
+
+impl BrokerServices {
+    pub fn prepare_lockdown_self(&self, _policy: &::Policy, _rule_paths: &[PathBuf]) -> io::Result<Policy> {
+        // FIXME: crsio2 only supports lowering the token of a target it spawned
+        Err(io::Error::new(io::ErrorKind::Other, "locking down the current process is not supported on Windows"))
+    }
+
+    pub fn lockdown_self(&mut self, _policy: &Policy) -> io::Result<()> {
+        unreachable!("prepare_lockdown_self always fails on Windows")
+    }
+}
+
+impl Policy {
+    pub(crate) fn learning_mode(&self) -> bool {
+        // Rejected when building the policy
+        false
+    }
+
+    pub(crate) fn violation_action(&self) -> ViolationAction {
+        // Other actions are rejected when building the policy
+        ViolationAction::Deny
+    }
+
+    pub(crate) fn encode_fingerprint(&self, _encoding: &mut Vec<u8>) {
+        // Everything crsio2 is given follows from the preset, which is already encoded
+    }
+
+    pub(crate) fn is_subset_of(&self, _outer: &Policy) -> bool {
+        // Zygotes, which bound the policies of their targets, are not supported
+        true
+    }
+
+    pub(crate) fn bound_rule_path(path: &Path) -> PathBuf {
+        // FIXME: rules aren't enforced yet, so there is nothing to resolve them for
+        path.to_owned()
+    }
+}
+
+/// Wakes the task polling it when a watched child exits or reaches its deadline. Each child is
+/// waited for on a thread of its own, since there is no reactor to register process handles with.
+pub struct ExitWatcher {
+    task: Arc<AtomicTask>,
+}
+
+impl ExitWatcher {
+    pub(crate) fn new(_broker: &::BrokerServices) -> io::Result<Self> {
+        Ok(ExitWatcher {
+            task: Arc::new(AtomicTask::new()),
+        })
+    }
+
+    pub(crate) fn watch(&mut self, child: &Child, deadline: Option<Instant>) -> io::Result<()> {
+        // The Child keeps its own handle open, so the ID can't have been reused
+        let handle = unsafe { OpenProcess(SYNCHRONIZE, FALSE, child.id()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let handle = handle as usize;
+        let timeout = match deadline {
+            Some(deadline) => {
+                // Round up, so the deadline has passed once the wait times out
+                let now = Instant::now();
+                let remaining = if deadline > now { deadline - now } else { Default::default() };
+                let millis = remaining.as_secs() * 1000 + ((remaining.subsec_nanos() + 999_999) / 1_000_000) as u64;
+                cmp::min(millis, (INFINITE - 1) as u64) as u32
+            },
+            None => INFINITE,
+        };
+        let task = self.task.clone();
+        thread::spawn(move || {
+            unsafe {
+                // A child that reaches its deadline is killed, so keep waiting for it to exit
+                if WaitForSingleObject(handle as _, timeout) == WAIT_TIMEOUT {
+                    task.notify();
+                    WaitForSingleObject(handle as _, INFINITE);
+                }
+                CloseHandle(handle as _);
+            }
+            task.notify();
+        });
+        Ok(())
+    }
+
+    /// Registers the current task to be woken by the next notification. Callers must check every
+    /// child afterwards, since any of them may have caused a notification.
+    pub(crate) fn poll(&mut self) -> io::Result<()> {
+        self.task.register();
+        Ok(())
+    }
+}
+
+pub struct Zygote {
+    _private: (),
+}
+
+impl Zygote {
+    pub fn spawn(_services: &mut ::BrokerServices, _command: &mut Command, _resolved: Resolved) -> io::Result<Self> {
+        // FIXME: Windows has no equivalent of fork
+        Err(io::Error::new(io::ErrorKind::Other, "zygotes are not supported on Windows"))
+    }
+
+    pub fn fork(&mut self, _services: &mut ::BrokerServices, _policy: &::Policy, _rule_paths: Vec<PathBuf>) -> io::Result<Child> {
+        Err(io::Error::new(io::ErrorKind::Other, "zygotes are not supported on Windows"))
+    }
+}
+
+// FILETIME durations are in units of 100ns
+fn filetime_duration(time: &FILETIME) -> Duration {
+    let ticks = ((time.dwHighDateTime as u64) << 32) | (time.dwLowDateTime as u64);
+    Duration::new(ticks / 10_000_000, ((ticks % 10_000_000) * 100) as u32)
+}
+
+/// Returns the signal that terminated a process. Windows has no signals, so this is always `None`.
+pub(crate) fn termination_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// The signal that indicates a process was killed for violating its policy.
+pub(crate) const VIOLATION_SIGNAL: Option<i32> = None;
+
+/// Kills a process by ID, for use when the `Child` is owned by another thread. The caller must
+/// ensure the process handle is still open, so the ID cannot have been reused.
+pub fn kill_process(process_id: u32) -> io::Result<()> {
+    unsafe {
+        let handle = OpenProcess(PROCESS_TERMINATE, FALSE, process_id);
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let result = winapi_bool_call!(TerminateProcess(handle, 1));
+        CloseHandle(handle);
+        result?;
+        Ok(())
+    }
 }
 
 impl PolicyBuilder {
     pub fn new(broker: &mut ::BrokerServices, preset: PolicyPreset) -> Self {
         let mut policy = broker.inner.inner.create_policy();
+        let mut unsupported_preset = None;
         match preset {
             PolicyPreset::ComputeOnly => {
                 policy.set_token_level(TokenLevel::RestrictedSameAccess, TokenLevel::Lockdown).expect("failed to set token level");
                 // FIXME
-                PolicyBuilder { inner: policy }
             },
             PolicyPreset::Unrestricted => {
-                unimplemented!()
+                policy.set_token_level(TokenLevel::Unprotected, TokenLevel::Unprotected).expect("failed to set token level");
             },
+            // FIXME: these need file and network rules, which we don't expose from crsio2 yet
+            PolicyPreset::ReadOnlyFilesystem => unsupported_preset = Some("ReadOnlyFilesystem"),
+            PolicyPreset::NetworkClient => unsupported_preset = Some("NetworkClient"),
+            PolicyPreset::FileConverter => unsupported_preset = Some("FileConverter"),
+            PolicyPreset::Interpreter { .. } => unsupported_preset = Some("Interpreter"),
         }
+        PolicyBuilder { inner: policy, learning_mode: false, violation_action: ViolationAction::Deny, unsupported_preset }
+    }
+
+    pub fn set_learning_mode(&mut self, enabled: bool) {
+        self.learning_mode = enabled;
+    }
+
+    pub fn set_violation_action(&mut self, action: ViolationAction) {
+        self.violation_action = action;
     }
 
-    pub fn build(self) -> io::Result<Policy> {
+    pub fn build(self, rules: &[PathRule]) -> io::Result<Policy> {
+        if self.learning_mode {
+            return Err(io::Error::new(io::ErrorKind::Other, "learning mode is not supported on Windows"));
+        }
+        if self.violation_action != ViolationAction::Deny {
+            // FIXME: restricted tokens only ever fail the operation
+            return Err(io::Error::new(io::ErrorKind::Other, "violation actions other than Deny are not supported on Windows"));
+        }
+        if let Some(preset) = self.unsupported_preset {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("policy preset {} is not supported on Windows", preset)));
+        }
+        if !rules.is_empty() {
+            // FIXME: translate to crsio2 file rules
+            return Err(io::Error::new(io::ErrorKind::Other, "path rules are not supported on Windows"));
+        }
         Ok(Policy {
             inner: self.inner,
         })
@@ -1,22 +1,110 @@
 use ::{platform, BrokerServices};
+use command::{env_pattern_matches};
 
-use std::{io};
+use std::{io, fmt};
+use std::collections::{HashMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
+use sha2::{Sha256, Digest};
+
 #[derive(Clone)]
 pub struct Policy(pub(crate) Arc<_Policy>);
 
 pub(crate) struct _Policy {
     pub(crate) inner: platform::Policy,
+    preset: PolicyPreset,
+    pub(crate) rules: Vec<PathRule>,
+    denied_envs: Vec<String>,
+    fingerprint: PolicyFingerprint,
 }
 
 pub struct PolicyBuilder {
     pub(crate) inner: platform::PolicyBuilder,
+    preset: PolicyPreset,
+    rules: Vec<PathRule>,
+    denied_envs: Vec<String>,
+    error: Option<io::Error>,
+}
+
+/// A SHA-256 hash identifying everything that determines how a `Policy` is enforced.
+/// 
+/// The fingerprint covers the preset, the path rules, `learning_mode`, `on_violation`, the denied
+/// environment variables and any platform-specific options (such as the default access set with
+/// the macOS `PolicyBuilderExt`). Two policies with the same fingerprint compile to the same
+/// filter, regardless of the order in which rules were added. Since platform-specific state is
+/// included, fingerprints are only comparable between policies built on the same platform.
+/// Parameter values bound by `Command` are not included, so every child spawned from a policy
+/// shares its fingerprint.
+/// 
+/// The hash is computed over a versioned encoding of the policy, so a fingerprint stays the same
+/// across releases unless the encoding's version changes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PolicyFingerprint(pub [u8; 32]);
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub(crate) struct PathRule {
+    pub(crate) path: PathTemplate,
+    pub(crate) access: PathAccess,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub(crate) enum PathAccess {
+    Read,
+    ReadWrite,
+}
+
+/// A path that may reference parameters (e.g. `${JOB_DIR}/output`), which are bound separately
+/// for each `Command`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub(crate) struct PathTemplate(Vec<TemplatePart>);
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+enum TemplatePart {
+    Literal(String),
+    Parameter(String),
+}
+
+/// The rule set a policy starts from.
+/// 
+/// Except for `Unrestricted`, every preset denies anything it does not explicitly allow. Resources
+/// acquired before `TargetServices::lockdown` (loaded libraries, open file descriptors) remain
+/// usable regardless of the preset.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum PolicyPreset {
+    /// No access to the filesystem, the network, or other processes.
     ComputeOnly,
+    /// No restrictions at all.
     Unrestricted,
+    /// Read access to the entire filesystem, but no writes. No network access.
+    ReadOnlyFilesystem,
+    /// Outbound network connections and name resolution (including reading the system's resolver
+    /// configuration). No other filesystem access.
+    NetworkClient,
+    /// Reading from and writing to the standard streams and `/dev/null`. Any other file must be
+    /// opened by the broker and passed to the child.
+    FileConverter,
+    /// Read access to the install directory of a language runtime (e.g. the directory containing
+    /// an interpreter and its standard library), so modules can be loaded after lockdown. No writes.
+    Interpreter {
+        install_dir: PathBuf,
+    },
+}
+
+/// What happens when a child attempts an operation its policy denies.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum ViolationAction {
+    /// The operation fails (typically with `EPERM`) and the child carries on. This is the default.
+    Deny,
+    /// The child is killed with `SIGSYS`, which `Child::wait_termination` reports as
+    /// `Termination::Violation`. Not supported on Windows.
+    Kill,
+    /// As with `Kill`, but `TargetServices::lockdown` first installs a `SIGSYS` handler that
+    /// reports the violating system call to the broker before the child dies. The report is
+    /// available from `Child::violation_report`. Intended for debugging policies. Not supported on
+    /// Windows.
+    Trap,
 }
 
 impl Policy {
@@ -27,19 +115,391 @@ impl Policy {
     pub fn compute_only(broker: &mut BrokerServices) -> io::Result<Policy> {
         PolicyBuilder::new(broker, PolicyPreset::ComputeOnly).build()
     }
+
+    pub fn fingerprint(&self) -> PolicyFingerprint {
+        self.0.fingerprint
+    }
+
+    pub(crate) fn env_denied(&self, name: &OsStr) -> bool {
+        self.0.denied_envs.iter().any(|pattern| env_pattern_matches(pattern, name))
+    }
+
+    /// Verifies that everything this policy allows is also allowed by `outer`, given the resolved
+    /// paths of both policies' rules.
+    /// 
+    /// The check is conservative: it may reject policies that are subsets in practice (e.g. a
+    /// preset whose rights are granted by individual rules in `outer`).
+    pub(crate) fn check_subset_of(&self, outer: &Policy, rule_paths: &[PathBuf], outer_rule_paths: &[PathBuf]) -> io::Result<()> {
+        let not_subset = |reason: String| {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("policy is not a subset of the policy bounding it: {}", reason)))
+        };
+        if self.0.inner.learning_mode() || outer.0.inner.learning_mode() {
+            return not_subset("policies in learning mode cannot bound or be bounded".to_owned());
+        }
+        if !preset_covers(&outer.0.preset, &self.0.preset) {
+            return not_subset(format!("preset {:?} allows more than {:?}", self.0.preset, outer.0.preset));
+        }
+        // Compare paths as the sandbox will match them, so neither symlinks nor `..` can make a path
+        // appear to be beneath one it isn't
+        let outer_rule_paths: Vec<PathBuf> = outer_rule_paths.iter().map(|path| platform::Policy::bound_rule_path(path)).collect();
+        for (rule, path) in self.0.rules.iter().zip(rule_paths.iter()) {
+            let path = platform::Policy::bound_rule_path(path);
+            let covered_by_preset = match outer.0.preset {
+                PolicyPreset::Unrestricted => true,
+                PolicyPreset::ReadOnlyFilesystem => rule.access == PathAccess::Read,
+                _ => false,
+            };
+            let escapes = path.components().any(|component| component == Component::ParentDir);
+            let covered_by_rule = !escapes && outer.0.rules.iter().zip(outer_rule_paths.iter())
+                .any(|(outer_rule, outer_path)| outer_rule.access >= rule.access && path.starts_with(outer_path));
+            if !covered_by_preset && !covered_by_rule {
+                return not_subset(format!("access to {:?} is not allowed by the bounding policy", path));
+            }
+        }
+        if !self.0.inner.is_subset_of(&outer.0.inner) {
+            return not_subset("platform-specific options allow more than the bounding policy".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Substitutes the parameters bound by a `Command` into the path of each rule, in the order
+    /// the rules were added. Fails if any referenced parameter is unbound.
+    pub(crate) fn resolve_rules(&self, parameters: &HashMap<String, OsString>) -> io::Result<Vec<PathBuf>> {
+        self.0.rules.iter().map(|rule| rule.path.resolve(parameters)).collect()
+    }
 }
 
 impl PolicyBuilder {
     pub fn new(broker: &mut BrokerServices, preset: PolicyPreset) -> Self {
         PolicyBuilder {
-            inner: platform::PolicyBuilder::new(broker, preset)
+            inner: platform::PolicyBuilder::new(broker, preset.clone()),
+            preset,
+            rules: Vec::new(),
+            denied_envs: DEFAULT_DENIED_ENVS.iter()
+                .chain(platform::RESERVED_ENV_VARS.iter())
+                .map(|&pattern| pattern.to_owned())
+                .collect(),
+            error: None,
+        }
+    }
+
+    /// Allows reading files and listing directories at or beneath `path`.
+    /// 
+    /// The path may reference parameters with `${NAME}` syntax, which are substituted with the
+    /// values bound by `Command::param` when each child is spawned. After substitution the path
+    /// must be absolute.
+    pub fn allow_read(&mut self, path: &str) -> &mut Self {
+        self.add_rule(path, PathAccess::Read)
+    }
+
+    /// Allows reading, creating, modifying and deleting files at or beneath `path`.
+    /// 
+    /// The path may reference parameters in the same manner as `allow_read`.
+    pub fn allow_read_write(&mut self, path: &str) -> &mut Self {
+        self.add_rule(path, PathAccess::ReadWrite)
+    }
+
+    fn add_rule(&mut self, path: &str, access: PathAccess) -> &mut Self {
+        match PathTemplate::parse(path) {
+            Ok(path) => self.rules.push(PathRule { path, access }),
+            // Errors are reported by build so rules can be chained
+            Err(err) => if self.error.is_none() {
+                self.error = Some(err);
+            },
         }
+        self
+    }
+
+    // Adds a rule for a path that may contain `${`, which must not be taken for a parameter
+    fn add_literal_rule(&mut self, path: &Path, access: PathAccess) {
+        match path.to_str() {
+            Some(path) => self.rules.push(PathRule { path: PathTemplate(vec![TemplatePart::Literal(path.to_owned())]), access }),
+            None => if self.error.is_none() {
+                self.error = Some(io::Error::new(io::ErrorKind::InvalidInput, format!("policy path {:?} is not valid UTF-8", path)));
+            },
+        }
+    }
+
+    /// Prevents environment variables whose names match `pattern` from being passed to children,
+    /// in which `*` matches any sequence of characters.
+    /// 
+    /// Variables that alter dynamic loading (`LD_PRELOAD`, `DYLD_*`, etc.) and the variables used
+    /// internally by the sandbox are always denied.
+    pub fn deny_env(&mut self, pattern: &str) -> &mut Self {
+        self.denied_envs.push(pattern.to_owned());
+        self
+    }
+
+    /// Records operations instead of enforcing the policy.
+    /// 
+    /// Children spawned with a policy built in learning mode run unconfined, but every operation
+    /// they perform after lockdown is recorded. Once the child has exited `Child::learned_policy`
+    /// returns a minimal policy that would have allowed those operations, which can be used as a
+    /// starting point for a least-privilege policy. The preset and rules are ignored.
+    pub fn learning_mode(&mut self, enabled: bool) -> &mut Self {
+        self.inner.set_learning_mode(enabled);
+        self
+    }
+
+    /// Sets what happens when a child attempts an operation the policy denies. Ignored in learning
+    /// mode, where nothing is denied.
+    pub fn on_violation(&mut self, action: ViolationAction) -> &mut Self {
+        self.inner.set_violation_action(action);
+        self
     }
 
     pub fn build(self) -> io::Result<Policy> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        let inner = self.inner.build(&self.rules)?;
+        let fingerprint = fingerprint(&self.preset, &self.rules, &self.denied_envs, &inner);
         Ok(Policy(Arc::new(_Policy {
-            inner: self.inner.build()?,
+            inner,
+            preset: self.preset,
+            rules: self.rules,
+            denied_envs: self.denied_envs,
+            fingerprint,
         })))
     }
 }
 
+/// The operations a child performed under a policy in learning mode, as a minimal policy that
+/// would have allowed them. Returned by `Child::learned_policy`.
+/// 
+/// Rules allow access to everything beneath their path, so paths beneath another path with at
+/// least the same access are omitted. `Display` writes one item per line in sorted order, so the
+/// documents learned from different runs can be diffed.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LearnedPolicy {
+    /// `NetworkClient` if the child used the network, `ComputeOnly` otherwise.
+    pub preset: PolicyPreset,
+    /// Paths the child read from, for `PolicyBuilder::allow_read`.
+    pub read: Vec<PathBuf>,
+    /// Paths the child wrote to, for `PolicyBuilder::allow_read_write`.
+    pub read_write: Vec<PathBuf>,
+    /// Operations no rule can allow on its own, in the platform's terms (e.g. reading only the
+    /// metadata of a directory, or looking up a system service). The presets allow those most
+    /// programs need.
+    pub other: Vec<String>,
+}
+
+impl LearnedPolicy {
+    pub(crate) fn new(network: bool, read: BTreeSet<PathBuf>, read_write: BTreeSet<PathBuf>, other: BTreeSet<String>) -> LearnedPolicy {
+        // Sorted order puts every path right after its ancestors
+        let mut minimal_read_write: Vec<PathBuf> = Vec::new();
+        for path in read_write {
+            if !minimal_read_write.iter().any(|ancestor| path.starts_with(ancestor)) {
+                minimal_read_write.push(path);
+            }
+        }
+        let mut minimal_read: Vec<PathBuf> = Vec::new();
+        for path in read {
+            let covered = |ancestor: &PathBuf| path.starts_with(ancestor);
+            if !minimal_read.iter().any(&covered) && !minimal_read_write.iter().any(&covered) {
+                minimal_read.push(path);
+            }
+        }
+        LearnedPolicy {
+            preset: if network { PolicyPreset::NetworkClient } else { PolicyPreset::ComputeOnly },
+            read: minimal_read,
+            read_write: minimal_read_write,
+            other: other.into_iter().collect(),
+        }
+    }
+
+    /// Creates a builder for a policy with the learned preset and rules. Operations in `other`
+    /// are only allowed if the preset allows them.
+    pub fn builder(&self, broker: &mut BrokerServices) -> PolicyBuilder {
+        let mut builder = PolicyBuilder::new(broker, self.preset.clone());
+        for path in self.read.iter() {
+            builder.add_literal_rule(path, PathAccess::Read);
+        }
+        for path in self.read_write.iter() {
+            builder.add_literal_rule(path, PathAccess::ReadWrite);
+        }
+        builder
+    }
+}
+
+impl fmt::Display for LearnedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "preset {:?}", self.preset)?;
+        for path in self.read.iter() {
+            writeln!(f, "read {}", path.display())?;
+        }
+        for path in self.read_write.iter() {
+            writeln!(f, "read_write {}", path.display())?;
+        }
+        for operation in self.other.iter() {
+            writeln!(f, "other {}", operation)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PolicyFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PolicyFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PolicyFingerprint({})", self)
+    }
+}
+
+impl PathTemplate {
+    fn parse(template: &str) -> io::Result<PathTemplate> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("${") {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..].find('}')
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unterminated parameter reference in path {:?}", template)))?
+                + start;
+            let name = &rest[(start + 2)..end];
+            if !is_valid_parameter_name(name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid parameter name {:?} in path {:?}", name, template)));
+            }
+            parts.push(TemplatePart::Parameter(name.to_owned()));
+            rest = &rest[(end + 1)..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_owned()));
+        }
+        Ok(PathTemplate(parts))
+    }
+
+    fn resolve(&self, parameters: &HashMap<String, OsString>) -> io::Result<PathBuf> {
+        let mut path = OsString::new();
+        for part in self.0.iter() {
+            match part {
+                TemplatePart::Literal(literal) => path.push(literal),
+                TemplatePart::Parameter(name) => {
+                    let value = parameters.get(name)
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("policy parameter ${{{}}} is not bound", name)))?;
+                    path.push(value);
+                },
+            }
+        }
+        let path = PathBuf::from(path);
+        if !path.is_absolute() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("policy path {:?} is not absolute", path)));
+        }
+        Ok(path)
+    }
+}
+
+// Version 2 of the encoding hashed by `fingerprint`. Every item is written with an explicit tag,
+// and every variable-length field with its length, so distinct policies can't encode the same way.
+// Any change to what is encoded or how must bump the version.
+const FINGERPRINT_VERSION: &[u8] = b"sandbox policy fingerprint v2";
+
+fn fingerprint(preset: &PolicyPreset, rules: &[PathRule], denied_envs: &[String], inner: &platform::Policy) -> PolicyFingerprint {
+    let mut encoding = Vec::new();
+    encode_bytes(&mut encoding, FINGERPRINT_VERSION);
+    match preset {
+        PolicyPreset::ComputeOnly => encode_bytes(&mut encoding, b"compute-only"),
+        PolicyPreset::Unrestricted => encode_bytes(&mut encoding, b"unrestricted"),
+        PolicyPreset::ReadOnlyFilesystem => encode_bytes(&mut encoding, b"read-only-filesystem"),
+        PolicyPreset::NetworkClient => encode_bytes(&mut encoding, b"network-client"),
+        PolicyPreset::FileConverter => encode_bytes(&mut encoding, b"file-converter"),
+        PolicyPreset::Interpreter { install_dir } => {
+            encode_bytes(&mut encoding, b"interpreter");
+            encode_bytes(&mut encoding, &os_str_bytes(install_dir.as_os_str()));
+        },
+    }
+    let mut rules: Vec<&PathRule> = rules.iter().collect();
+    rules.sort();
+    rules.dedup();
+    encode_length(&mut encoding, rules.len());
+    for rule in rules {
+        match rule.access {
+            PathAccess::Read => encode_bytes(&mut encoding, b"read"),
+            PathAccess::ReadWrite => encode_bytes(&mut encoding, b"read-write"),
+        }
+        encode_length(&mut encoding, rule.path.0.len());
+        for part in rule.path.0.iter() {
+            match part {
+                TemplatePart::Literal(literal) => {
+                    encode_bytes(&mut encoding, b"literal");
+                    encode_bytes(&mut encoding, literal.as_bytes());
+                },
+                TemplatePart::Parameter(name) => {
+                    encode_bytes(&mut encoding, b"parameter");
+                    encode_bytes(&mut encoding, name.as_bytes());
+                },
+            }
+        }
+    }
+    encode_bytes(&mut encoding, if inner.learning_mode() { b"learning" } else { b"enforcing" });
+    match inner.violation_action() {
+        ViolationAction::Deny => encode_bytes(&mut encoding, b"deny"),
+        ViolationAction::Kill => encode_bytes(&mut encoding, b"kill"),
+        ViolationAction::Trap => encode_bytes(&mut encoding, b"trap"),
+    }
+    let denied_envs: BTreeSet<&String> = denied_envs.iter().collect();
+    encode_length(&mut encoding, denied_envs.len());
+    for pattern in denied_envs {
+        encode_bytes(&mut encoding, pattern.as_bytes());
+    }
+    inner.encode_fingerprint(&mut encoding);
+
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&Sha256::digest(&encoding));
+    PolicyFingerprint(hash)
+}
+
+pub(crate) fn encode_length(encoding: &mut Vec<u8>, length: usize) {
+    let length = length as u64;
+    for shift in (0..8).rev() {
+        encoding.push((length >> (shift * 8)) as u8);
+    }
+}
+
+pub(crate) fn encode_bytes(encoding: &mut Vec<u8>, bytes: &[u8]) {
+    encode_length(encoding, bytes.len());
+    encoding.extend_from_slice(bytes);
+}
+
+// The platform's native representation of a path, as bytes
+#[cfg(unix)]
+fn os_str_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_owned()
+}
+
+#[cfg(windows)]
+fn os_str_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    s.encode_wide().flat_map(|unit| vec![(unit >> 8) as u8, unit as u8]).collect()
+}
+
+// Whether every right granted by the `inner` preset is also granted by `outer`
+fn preset_covers(outer: &PolicyPreset, inner: &PolicyPreset) -> bool {
+    match (outer, inner) {
+        (outer, inner) if outer == inner => true,
+        (PolicyPreset::Unrestricted, _) => true,
+        (_, PolicyPreset::ComputeOnly) => true,
+        (PolicyPreset::ReadOnlyFilesystem, PolicyPreset::Interpreter { .. }) => true,
+        _ => false,
+    }
+}
+
+const DEFAULT_DENIED_ENVS: &[&str] = &[
+    "LD_PRELOAD",
+    "LD_AUDIT",
+    "DYLD_*",
+];
+
+pub(crate) fn is_valid_parameter_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
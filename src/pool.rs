@@ -0,0 +1,253 @@
+use ::{platform, BrokerServices, Child, Policy};
+use function::{server_command, call_request, call_in, decode_result, request_exit};
+
+use std::{io, mem, thread};
+use std::sync::{Mutex, Condvar};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use serde::{Serialize};
+use serde::de::{DeserializeOwned};
+
+/// A pool of locked-down children of the current executable that run functions registered with
+/// `TargetServices::serve`.
+///
+/// Workers are spawned ahead of time so jobs don't pay for process creation and policy exchange.
+/// A worker is replaced after it has run a configured number of jobs, when it crashes, and when a
+/// job exceeds its timeout. Replacements are spawned with the broker passed to `call`. When every
+/// worker is busy, callers wait in a queue.
+pub struct WorkerPool {
+    policy: Policy,
+    config: WorkerPoolConfig,
+    state: Mutex<PoolState>,
+    worker_available: Condvar,
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkerPoolConfig {
+    /// The number of workers kept alive.
+    pub size: usize,
+    /// The number of jobs a worker runs before it is replaced. `None` keeps workers until they crash.
+    pub max_jobs_per_worker: Option<usize>,
+    /// How long a job may run before its worker is killed. `None` allows jobs to run indefinitely.
+    pub job_timeout: Option<Duration>,
+}
+
+/// A snapshot of the state of a `WorkerPool`.
+#[derive(Clone, Debug, Default)]
+pub struct WorkerPoolMetrics {
+    /// The number of callers waiting for a worker.
+    pub queue_depth: usize,
+    pub idle_workers: usize,
+    pub busy_workers: usize,
+    pub jobs_completed: u64,
+    /// Jobs that failed because their worker crashed, timed out, or could not be spawned.
+    pub jobs_failed: u64,
+    pub jobs_timed_out: u64,
+    /// Workers replaced after reaching `max_jobs_per_worker`.
+    pub workers_recycled: u64,
+    /// Workers replaced because they crashed or timed out.
+    pub workers_crashed: u64,
+}
+
+struct PoolState {
+    idle: Vec<Worker>,
+    // Spawned workers plus slots reserved for workers being spawned
+    live: usize,
+    metrics: WorkerPoolMetrics,
+}
+
+struct Worker {
+    child: Child,
+    jobs: usize,
+}
+
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        WorkerPoolConfig {
+            size: 4,
+            max_jobs_per_worker: None,
+            job_timeout: None,
+        }
+    }
+}
+
+impl WorkerPool {
+    /// Creates a pool and spawns all of its workers.
+    pub fn new(broker: &mut BrokerServices, policy: &Policy, config: WorkerPoolConfig) -> io::Result<Self> {
+        if config.size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "worker pool size must be at least one"));
+        }
+        let pool = WorkerPool {
+            policy: policy.clone(),
+            config,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                live: 0,
+                metrics: WorkerPoolMetrics::default(),
+            }),
+            worker_available: Condvar::new(),
+        };
+        for _ in 0..pool.config.size {
+            let worker = pool.spawn_worker(broker)?;
+            let mut state = pool.state.lock().unwrap();
+            state.live += 1;
+            state.idle.push(worker);
+        }
+        Ok(pool)
+    }
+
+    /// Runs the function registered as `function` on a worker, and returns its result.
+    ///
+    /// Results are reported as for `BrokerServices::call`. If the job exceeds the configured
+    /// timeout, an error of kind `TimedOut` is returned. Arguments that cannot be serialized and
+    /// results that cannot be deserialized are reported as errors without affecting any worker.
+    pub fn call<A, R>(&self, broker: &mut BrokerServices, function: &str, argument: &A) -> io::Result<Result<R, String>> where
+        A: Serialize,
+        R: DeserializeOwned,
+    {
+        let request = call_request(function, argument)?;
+        let mut worker = match self.acquire_worker(broker) {
+            Ok(worker) => worker,
+            Err(err) => {
+                self.state.lock().unwrap().metrics.jobs_failed += 1;
+                return Err(err);
+            },
+        };
+
+        let (result, killed) = match self.config.job_timeout {
+            Some(timeout) => {
+                let (done_tx, done_rx) = mpsc::channel::<()>();
+                let process_id = worker.child.id();
+                let watchdog = thread::spawn(move || {
+                    match done_rx.recv_timeout(timeout) {
+                        Err(RecvTimeoutError::Timeout) => {
+                            warn!("sandboxed worker {} exceeded job timeout, killing it", process_id);
+                            if let Err(err) = platform::kill_process(process_id) {
+                                error!("failed to kill sandboxed worker {}: {}", process_id, err);
+                            }
+                            true
+                        },
+                        _ => false,
+                    }
+                });
+                let result = call_in(&mut worker.child, &request);
+                mem::drop(done_tx);
+                // The watchdog must be finished before the worker can be reaped, so it never kills
+                // a reused process ID
+                let killed = watchdog.join().unwrap_or(false);
+                (result, killed)
+            },
+            None => (call_in(&mut worker.child, &request), false),
+        };
+        worker.jobs += 1;
+        // The watchdog may fire just after the result arrived, in which case the result stands
+        let timed_out = killed && result.is_err();
+
+        {
+            let mut state = self.state.lock().unwrap();
+            if result.is_ok() && !timed_out {
+                state.metrics.jobs_completed += 1;
+            } else {
+                state.metrics.jobs_failed += 1;
+                state.metrics.workers_crashed += 1;
+                if timed_out {
+                    state.metrics.jobs_timed_out += 1;
+                }
+            }
+        }
+
+        if timed_out {
+            self.replace_worker(broker, worker, false);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "sandboxed job exceeded its timeout"));
+        }
+        match result {
+            Ok(_) if killed => self.replace_worker(broker, worker, false),
+            Ok(_) if self.config.max_jobs_per_worker.map(|max| worker.jobs >= max).unwrap_or(false) => {
+                self.state.lock().unwrap().metrics.workers_recycled += 1;
+                self.replace_worker(broker, worker, true);
+            },
+            Ok(_) => self.release_worker(worker),
+            Err(_) => self.replace_worker(broker, worker, false),
+        }
+        decode_result(result?)
+    }
+
+    pub fn metrics(&self) -> WorkerPoolMetrics {
+        let state = self.state.lock().unwrap();
+        let mut metrics = state.metrics.clone();
+        metrics.idle_workers = state.idle.len();
+        metrics.busy_workers = state.live - state.idle.len();
+        metrics
+    }
+
+    fn acquire_worker(&self, broker: &mut BrokerServices) -> io::Result<Worker> {
+        let mut state = self.state.lock().unwrap();
+        state.metrics.queue_depth += 1;
+        loop {
+            if let Some(worker) = state.idle.pop() {
+                state.metrics.queue_depth -= 1;
+                return Ok(worker);
+            }
+            if state.live < self.config.size {
+                // A worker failed to be replaced earlier, so spawn one now
+                state.live += 1;
+                state.metrics.queue_depth -= 1;
+                mem::drop(state);
+                return self.spawn_worker(broker).map_err(|err| {
+                    self.state.lock().unwrap().live -= 1;
+                    err
+                });
+            }
+            state = self.worker_available.wait(state).unwrap();
+        }
+    }
+
+    fn release_worker(&self, worker: Worker) {
+        self.state.lock().unwrap().idle.push(worker);
+        self.worker_available.notify_one();
+    }
+
+    // Shuts down a worker and spawns a new one in its place
+    fn replace_worker(&self, broker: &mut BrokerServices, mut worker: Worker, graceful: bool) {
+        retire_worker(&mut worker, graceful);
+        match self.spawn_worker(broker) {
+            Ok(worker) => self.release_worker(worker),
+            Err(err) => {
+                error!("failed to spawn replacement sandboxed worker: {}", err);
+                self.state.lock().unwrap().live -= 1;
+                // Let a waiting caller try to spawn it instead
+                self.worker_available.notify_one();
+            },
+        }
+    }
+
+    fn spawn_worker(&self, broker: &mut BrokerServices) -> io::Result<Worker> {
+        let mut child = server_command(&self.policy)?.spawn(broker)?;
+        child.run()?;
+        Ok(Worker { child, jobs: 0 })
+    }
+
+    fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        for mut worker in state.idle.drain(..) {
+            retire_worker(&mut worker, true);
+        }
+        state.live = 0;
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn retire_worker(worker: &mut Worker, graceful: bool) {
+    if !graceful || request_exit(&mut worker.child).is_err() {
+        let _ = worker.child.kill();
+    }
+    if let Err(err) = worker.child.wait() {
+        warn!("error when waiting for sandboxed worker to exit: {}", err);
+    }
+}
@@ -0,0 +1,75 @@
+use std::{io, env, fs, process};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A private writable directory created for a child by `Command::scratch_dir`, which is deleted
+/// along with its contents when dropped.
+pub(crate) struct ScratchDir {
+    path: PathBuf,
+    size_limit: u64,
+}
+
+static SCRATCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl ScratchDir {
+    pub(crate) fn create(size_limit: u64) -> io::Result<Self> {
+        // The sandbox matches against resolved paths, so symlinks like /var -> /private/var
+        // must be resolved
+        let parent = fs::canonicalize(env::temp_dir())?;
+        loop {
+            let path = parent.join(format!("sandbox_scratch_{}_{}", process::id(), SCRATCH_COUNTER.fetch_add(1, Ordering::SeqCst)));
+            match create_private_dir(&path) {
+                Ok(()) => return Ok(ScratchDir { path, size_limit }),
+                // Left behind by an earlier process with the same ID
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        // Without a size-limited filesystem to back the directory the limit can't be enforced, so
+        // at least make it visible when it was exceeded
+        match dir_size(&self.path) {
+            Ok(size) if size > self.size_limit => warn!("scratch directory {:?} grew to {} bytes, exceeding its limit of {} bytes", self.path, size, self.size_limit),
+            Ok(_) => {},
+            Err(err) => debug!("failed to measure scratch directory {:?}: {}", self.path, err),
+        }
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            warn!("failed to remove scratch directory {:?}: {}", self.path, err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    fs::DirBuilder::new().mode(0o700).create(path)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    fs::create_dir(path)
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        // Doesn't follow symlinks, so links out of the directory aren't counted
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
@@ -1,6 +1,11 @@
-use ::{platform};
+use ::{platform, warm_up, Policy};
 
-use std::{panic, process};
+use std::{io, env, panic, process};
+use std::collections::HashMap;
+
+use serde::{Serialize};
+use serde::de::{DeserializeOwned};
+use json;
 
 pub struct BrokerServices {
     pub(crate) inner: platform::BrokerServices,
@@ -8,6 +13,7 @@ pub struct BrokerServices {
 
 pub struct TargetServices {
     pub(crate) inner: platform::TargetServices,
+    before_lockdown: Vec<Box<FnMut() -> io::Result<()>>>,
 }
 
 pub enum Services {
@@ -19,24 +25,100 @@ impl BrokerServices {
     pub(crate) fn new(inner: platform::BrokerServices) -> Self {
         BrokerServices { inner }
     }
+
+    /// Enacts `policy` on the calling process, without spawning a target.
+    /// 
+    /// This is intended for tools that sandbox themselves once they have finished initializing
+    /// (e.g. after parsing their configuration and opening their files).
+    /// 
+    /// Since the policy applies to the calling process, only the `${EXE_DIR}` parameter is bound;
+    /// other paths should be written literally. Problems found before anything is enacted (policies
+    /// in learning mode, rules that cannot be resolved or bound, or a platform that does not
+    /// support this, such as Windows) are returned as errors and leave the process unchanged. As
+    /// with `TargetServices::lockdown`, the process is aborted if enacting the policy itself fails.
+    pub fn lockdown_self(&mut self, policy: &Policy) -> io::Result<()> {
+        let mut params = HashMap::new();
+        if let Some(exe_dir) = env::current_exe()?.parent() {
+            params.insert("EXE_DIR".to_owned(), exe_dir.as_os_str().to_owned());
+        }
+        let rule_paths = policy.resolve_rules(&params)?;
+        let prepared = self.inner.prepare_lockdown_self(policy, &rule_paths)?;
+        let inner = &mut self.inner;
+        lockdown_or_abort(move || inner.lockdown_self(&prepared));
+        Ok(())
+    }
 }
 
 impl TargetServices {
     pub(crate) fn new(inner: platform::TargetServices) -> Self {
-        TargetServices { inner }
+        TargetServices { inner, before_lockdown: Vec::new() }
+    }
+
+    /// Registers a function to be run immediately before the policy is enacted by `lockdown`.
+    /// 
+    /// This allows acquiring resources the policy would deny (e.g. opening devices or loading data
+    /// files) so they remain usable afterwards. Hooks run in the order they were registered. If a
+    /// hook fails, `lockdown` aborts the process.
+    pub fn before_lockdown<F>(&mut self, f: F) -> &mut Self where
+        F: FnMut() -> io::Result<()> + 'static,
+    {
+        self.before_lockdown.push(Box::new(f));
+        self
+    }
+
+    /// Registers all of the built-in warm-ups in `sandbox::warm_up`, which prepare commonly used
+    /// system facilities (random numbers, time zones and locales) to work after lockdown.
+    pub fn warm_up_defaults(&mut self) -> &mut Self {
+        self
+            .before_lockdown(warm_up::rng)
+            .before_lockdown(warm_up::timezone)
+            .before_lockdown(warm_up::locale)
     }
 
+    /// Receives the policy from the broker and enacts it on the calling process.
+    /// 
+    /// The policy applies to every thread in the process, including threads started before
+    /// lockdown (such as the reactor thread used for IPC), not just the calling thread. If the
+    /// policy cannot be enacted for any reason, the process is aborted.
     pub fn lockdown(&mut self) {
-        // If lockdown fails for any reason, force process to exit immediately
-        let this = panic::AssertUnwindSafe(self);
-        if let Err(err) = panic::catch_unwind(move || {
-            if let Err(err) = this.0.inner.lockdown() {
-                error!("error when trying to lockdown sandbox: {}", err);
-                process::abort();
+        lockdown_or_abort(|| {
+            for hook in self.before_lockdown.iter_mut() {
+                hook()?;
             }
-        }) {
-            error!("panic when trying to lockdown sandbox: {:?}", err);
+            self.inner.lockdown()
+        })
+    }
+
+    /// Sends a message to the broker, which it can receive with `Child::recv_message`.
+    /// 
+    /// Only available after lockdown.
+    pub fn send_message<T: Serialize>(&mut self, message: &T) -> io::Result<()> {
+        let message = json::to_string(message).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.inner.send_message(message)
+    }
+
+    /// Receives a message sent by the broker with `Child::send_message`, blocking until one is
+    /// available. Returns `None` if the broker has closed the channel.
+    /// 
+    /// Only available after lockdown.
+    pub fn recv_message<T: DeserializeOwned>(&mut self) -> io::Result<Option<T>> {
+        match self.inner.recv_message()? {
+            Some(message) => Ok(Some(json::from_str(&message).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn lockdown_or_abort<F: FnOnce() -> io::Result<()>>(f: F) {
+    // If lockdown fails for any reason, force process to exit immediately
+    let f = panic::AssertUnwindSafe(f);
+    if let Err(err) = panic::catch_unwind(move || {
+        if let Err(err) = (f.0)() {
+            error!("error when trying to lockdown sandbox: {}", err);
             process::abort();
         }
+    }) {
+        error!("panic when trying to lockdown sandbox: {:?}", err);
+        process::abort();
     }
 }
\ No newline at end of file
@@ -0,0 +1,236 @@
+use ::{platform, BrokerServices, Child, Termination, ViolationAction};
+
+use std::{io};
+use std::collections::VecDeque;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+use futures::{future, Async, Poll, Stream};
+use serde::{Serialize};
+use tokio::current_thread::block_on_all;
+
+/// Owns a set of running children and reports what happens to them as a single stream of events.
+///
+/// Children are identified by their process IDs. Each child produces any number of `Message`
+/// events followed by exactly one terminating event, after which it is removed from the
+/// supervisor. Dropping the supervisor kills and reaps every child it still owns.
+/// 
+/// The supervisor is a `Stream` of events, which is woken by the broker's reactor when a child
+/// sends a message, exits or reaches its timeout. `next_event` waits for the next event outside of
+/// an executor.
+pub struct Supervisor {
+    children: Vec<Supervised>,
+    events: VecDeque<SupervisorEvent>,
+    watcher: platform::ExitWatcher,
+}
+
+#[derive(Debug)]
+pub enum SupervisorEvent {
+    /// The child sent a message with `TargetServices::send_message`. The message is JSON, as
+    /// received by `Child::recv_message`.
+    Message {
+        id: u32,
+        message: String,
+    },
+    /// The child exited by itself.
+    Exited {
+        id: u32,
+        status: ExitStatus,
+    },
+    /// The child was terminated by a signal, other than one reported as a violation.
+    Signaled {
+        id: u32,
+        signal: i32,
+    },
+    /// The child was killed for violating its policy.
+    Violation {
+        id: u32,
+    },
+    /// The child exceeded the timeout it was added with, and was killed.
+    TimedOut {
+        id: u32,
+    },
+    /// Waiting for or killing the child failed. It has been killed if possible, and is no longer
+    /// supervised.
+    Failed {
+        id: u32,
+        error: io::Error,
+    },
+}
+
+struct Supervised {
+    child: Child,
+    deadline: Option<Instant>,
+    timed_out: bool,
+    // Cleared once the target closes its channel, or if the child has none
+    receiving: bool,
+}
+
+impl Supervisor {
+    /// Creates a supervisor for children spawned by `broker`.
+    pub fn new(broker: &BrokerServices) -> io::Result<Self> {
+        Ok(Supervisor {
+            children: Vec::new(),
+            events: VecDeque::new(),
+            watcher: platform::ExitWatcher::new(broker)?,
+        })
+    }
+
+    /// Adds a child, which should already be running. Returns the ID used for it in events.
+    pub fn add(&mut self, child: Child) -> io::Result<u32> {
+        self.add_supervised(child, None)
+    }
+
+    /// Adds a child that is killed if it is still running after `timeout`.
+    pub fn add_with_timeout(&mut self, child: Child, timeout: Duration) -> io::Result<u32> {
+        self.add_supervised(child, Some(Instant::now() + timeout))
+    }
+
+    /// Sends a message to a supervised child, as with `Child::send_message`.
+    pub fn send_message<T: Serialize>(&mut self, id: u32, message: &T) -> io::Result<()> {
+        match self.children.iter_mut().find(|supervised| supervised.child.id() == id) {
+            Some(supervised) => supervised.child.send_message(message),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("process {} is not supervised", id))),
+        }
+    }
+
+    /// The number of children that have not terminated yet.
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Blocks until a child has something to report. Returns `None` once every child has terminated
+    /// and all events have been returned.
+    pub fn next_event(&mut self) -> io::Result<Option<SupervisorEvent>> {
+        block_on_all(future::poll_fn(|| self.poll()))
+    }
+
+    /// Kills and reaps every child, along with their descendants if they were spawned with
+    /// `Command::new_process_group`. No events are reported for them.
+    pub fn kill_all(&mut self) {
+        for mut supervised in self.children.drain(..) {
+            let id = supervised.child.id();
+            // Descendants in the child's process group may outlive it, so kill them regardless.
+            // Supervised children are never reaped before they're removed, so this is still safe.
+            if let Err(err) = supervised.child.kill_tree() {
+                if let Ok(None) = supervised.child.try_wait() {
+                    error!("failed to kill supervised process {}: {}", id, err);
+                }
+            }
+            if let Err(err) = supervised.child.wait() {
+                warn!("error when waiting for supervised process {} to exit: {}", id, err);
+            }
+        }
+        self.events.clear();
+    }
+
+    fn add_supervised(&mut self, child: Child, deadline: Option<Instant>) -> io::Result<u32> {
+        let id = child.id();
+        self.watcher.watch(&child.inner, deadline)?;
+        self.children.push(Supervised {
+            child,
+            deadline,
+            timed_out: false,
+            receiving: true,
+        });
+        Ok(id)
+    }
+
+    fn poll_children(&mut self) {
+        let now = Instant::now();
+        let mut index = 0;
+        while index < self.children.len() {
+            let terminated = {
+                let supervised = &mut self.children[index];
+                let id = supervised.child.id();
+                // Check for exit first, so messages sent right before exiting are still reported
+                let status = supervised.child.try_wait();
+                while supervised.receiving {
+                    match supervised.child.inner.poll_message() {
+                        Ok(Async::Ready(Some(message))) => self.events.push_back(SupervisorEvent::Message { id, message }),
+                        Ok(Async::Ready(None)) => supervised.receiving = false,
+                        Ok(Async::NotReady) => break,
+                        Err(err) => {
+                            // Children without a channel (e.g. spawned with lockdown_at_exec) end up here
+                            debug!("no longer receiving messages from supervised process {}: {}", id, err);
+                            supervised.receiving = false;
+                        },
+                    }
+                }
+                match status {
+                    Ok(Some(status)) => {
+                        self.events.push_back(termination_event(id, status, supervised.child.violation_action, supervised.timed_out));
+                        true
+                    },
+                    Ok(None) if !supervised.timed_out && supervised.deadline.map(|deadline| deadline <= now).unwrap_or(false) => {
+                        warn!("supervised process {} exceeded its timeout, killing it", id);
+                        match supervised.child.kill_tree() {
+                            Ok(_) => {
+                                supervised.timed_out = true;
+                                false
+                            },
+                            Err(error) => {
+                                self.events.push_back(SupervisorEvent::Failed { id, error });
+                                true
+                            },
+                        }
+                    },
+                    Ok(None) => false,
+                    Err(error) => {
+                        if let Err(err) = supervised.child.kill_tree() {
+                            debug!("failed to kill supervised process {} after an error: {}", id, err);
+                        }
+                        self.events.push_back(SupervisorEvent::Failed { id, error });
+                        true
+                    },
+                }
+            };
+            if terminated {
+                self.children.swap_remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+impl Stream for Supervisor {
+    type Item = SupervisorEvent;
+    type Error = io::Error;
+
+    /// Yields the next event, and ends once every child has terminated and all events have been
+    /// returned.
+    fn poll(&mut self) -> Poll<Option<SupervisorEvent>, io::Error> {
+        if self.events.is_empty() && !self.children.is_empty() {
+            // Register for notifications before checking, so none can be missed in between
+            self.watcher.poll()?;
+            self.poll_children();
+        }
+        match self.events.pop_front() {
+            Some(event) => Ok(Async::Ready(Some(event))),
+            None if self.children.is_empty() => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.kill_all();
+    }
+}
+
+fn termination_event(id: u32, status: ExitStatus, violation_action: ViolationAction, timed_out: bool) -> SupervisorEvent {
+    if timed_out {
+        return SupervisorEvent::TimedOut { id };
+    }
+    match Termination::from_status(status, violation_action) {
+        Termination::Violation => SupervisorEvent::Violation { id },
+        Termination::Signaled(signal) => SupervisorEvent::Signaled { id, signal },
+        Termination::Exited(_) => SupervisorEvent::Exited { id, status },
+    }
+}
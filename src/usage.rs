@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+/// Resources consumed by a child over its lifetime, collected when it is reaped.
+///
+/// Counters the platform does not report are zero, except for I/O byte counts which are `None`.
+///
+/// On macOS, the times, page faults and context switches are the totals for the child and any
+/// descendants it waited for, and `max_rss` is the largest of theirs. The I/O byte counts cover
+/// the child alone. On Windows, every field covers the child alone.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// The peak resident set size (working set on Windows), in bytes.
+    pub max_rss: u64,
+    /// Page faults serviced without I/O. On Windows, this counts all page faults.
+    pub minor_page_faults: u64,
+    /// Page faults that required I/O. Always zero on Windows.
+    pub major_page_faults: u64,
+    /// Always zero on Windows.
+    pub voluntary_context_switches: u64,
+    /// Always zero on Windows.
+    pub involuntary_context_switches: u64,
+    /// Bytes read from storage (on Windows, by any I/O operation).
+    pub read_bytes: Option<u64>,
+    /// Bytes written to storage (on Windows, by any I/O operation).
+    pub written_bytes: Option<u64>,
+}
@@ -0,0 +1,67 @@
+//! Built-in hooks for `TargetServices::before_lockdown`.
+//! 
+//! Many libraries lazily load system resources the first time they are used, which fails once the
+//! process has been locked down. Running these hooks before lockdown loads the resources ahead of
+//! time. They are best-effort: failures are logged, not returned, since the process can usually
+//! run without them.
+
+use std::{io};
+
+cfg_if! {
+    if #[cfg(target_os = "macos")] {
+        use libc::{self, c_int, c_void};
+    }
+}
+
+/// Draws random bytes from each of the system's random number sources, so any state they load
+/// lazily (such as a descriptor for the random device) is loaded before lockdown.
+/// 
+/// On macOS this covers `SecRandomCopyBytes`, which `rand::OsRng` and many other libraries use,
+/// and `getentropy`.
+pub fn rng() -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    {
+        let mut buffer = [0u8; 16];
+        if unsafe { SecRandomCopyBytes(kSecRandomDefault, buffer.len(), buffer.as_mut_ptr() as *mut c_void) } != 0 {
+            warn!("SecRandomCopyBytes failed");
+        }
+        if unsafe { getentropy(buffer.as_mut_ptr() as *mut c_void, buffer.len()) } != 0 {
+            warn!("getentropy failed: {}", io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Loads the local time zone, so time formatting does not need to read tzdata after lockdown.
+pub fn timezone() -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    unsafe { libc::tzset(); }
+    Ok(())
+}
+
+/// Sets the process's locale from the environment, as C programs do with `setlocale(LC_ALL, "")`,
+/// so the locale's data is loaded before lockdown.
+pub fn locale() -> io::Result<()> {
+    #[cfg(target_os = "macos")]
+    {
+        if unsafe { libc::setlocale(libc::LC_ALL, b"\0".as_ptr() as *const libc::c_char) }.is_null() {
+            warn!("failed to set locale from the environment");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+#[allow(non_upper_case_globals)]
+const kSecRandomDefault: *const c_void = 0 as *const c_void;
+
+#[cfg(target_os = "macos")]
+#[link(name = "Security", kind = "framework")]
+extern "C" {
+    fn SecRandomCopyBytes(rnd: *const c_void, count: usize, bytes: *mut c_void) -> c_int;
+}
+
+#[cfg(target_os = "macos")]
+extern "C" {
+    fn getentropy(buffer: *mut c_void, size: usize) -> c_int;
+}
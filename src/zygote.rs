@@ -0,0 +1,47 @@
+use ::{platform, BrokerServices, Child, Policy};
+
+use std::io;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// A sandbox-aware process that creates new targets by forking itself, spawned by
+/// `Command::spawn_zygote`.
+///
+/// Forking skips executing and initializing the program, which dominates spawning latency for
+/// large binaries. Each forked target has its own IPC channel and policy, and is otherwise a
+/// normal `Child`. Dropping the zygote kills any targets forked from it that are still running.
+///
+/// The zygote itself is never sandboxed: it runs with the broker's rights for as long as it
+/// lives, and only the targets it forks are locked down. The program must therefore be as trusted
+/// as the broker, and must not process untrusted input before `init`, since whatever state it
+/// builds up is inherited by every forked target.
+pub struct Zygote {
+    inner: platform::Zygote,
+    policy: Policy,
+    rule_paths: Vec<PathBuf>,
+    params: HashMap<String, OsString>,
+    kill_on_drop: bool,
+}
+
+impl Zygote {
+    pub(crate) fn new(inner: platform::Zygote, policy: Policy, rule_paths: Vec<PathBuf>, params: HashMap<String, OsString>, kill_on_drop: bool) -> Self {
+        Zygote { inner, policy, rule_paths, params, kill_on_drop }
+    }
+
+    /// Forks a new target, in a suspended state, which will enact `policy` when it calls
+    /// `TargetServices::lockdown`.
+    ///
+    /// The policy's parameters and `kill_on_drop` are set as they were for the zygote's command.
+    /// The policy must be a subset of the command's policy. As with `Command::spawn`, you must call
+    /// `Child::run` once you are ready for the target to start executing.
+    ///
+    /// Fails for policies built with `ViolationAction::Trap`, and if the descriptor number the
+    /// target's IPC channel needs is already in use in the zygote.
+    pub fn fork(&mut self, services: &mut BrokerServices, policy: &Policy) -> io::Result<Child> {
+        let rule_paths = policy.resolve_rules(&self.params)?;
+        policy.check_subset_of(&self.policy, &rule_paths, &self.rule_paths)?;
+        let inner = self.inner.fork(services, policy, rule_paths)?;
+        Ok(Child { inner, kill_on_drop: self.kill_on_drop, violation_action: policy.0.inner.violation_action(), scratch_dir: None })
+    }
+}
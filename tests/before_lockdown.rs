@@ -0,0 +1,74 @@
+extern crate sandbox;
+extern crate env_logger;
+extern crate rand;
+
+use std::{env, io, process};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+
+use rand::{OsRng, RngCore};
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy, Termination};
+
+fn main() {
+    // Skipped, since targets on Windows receive no arguments, which select the behavior
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+    assert_eq!(run_child(&mut broker, &policy, "hooks"), Termination::Exited(0));
+    // A failing hook must not leave the process running unconfined
+    match run_child(&mut broker, &policy, "failing") {
+        Termination::Signaled(_) => {},
+        termination => panic!("child with a failing hook terminated with {:?}", termination),
+    }
+}
+
+fn run_child(broker: &mut BrokerServices, policy: &Policy, behavior: &str) -> Termination {
+    let mut command = Command::new(env::current_exe().unwrap(), policy);
+    command
+        .arg(behavior)
+        .env_inherit("RUST_LOG");
+    let mut child = command.spawn(broker).unwrap();
+    child.run().unwrap();
+    child.wait_termination().unwrap()
+}
+
+fn run_target(mut target: TargetServices) {
+    match env::args().nth(1).unwrap().as_str() {
+        "hooks" => {
+            // Files opened by a hook remain usable, though the policy denies opening them
+            let opened = Rc::new(RefCell::new(None));
+            let hook_opened = opened.clone();
+            target
+                .before_lockdown(move || {
+                    *hook_opened.borrow_mut() = Some(File::open(env::current_exe()?)?);
+                    Ok(())
+                })
+                .warm_up_defaults();
+            target.lockdown();
+            assert!(File::open(env::current_exe().unwrap()).is_err());
+            let mut file = opened.borrow_mut().take().expect("hook did not run");
+            let mut header = [0u8; 4];
+            file.read_exact(&mut header).unwrap();
+
+            let mut buffer = [0u8; 16];
+            OsRng::new().unwrap().try_fill_bytes(&mut buffer).unwrap();
+        },
+        _ => {
+            target.before_lockdown(|| Err(io::Error::new(io::ErrorKind::Other, "hook failed")));
+            target.lockdown();
+            // Unreachable if lockdown aborted as it should
+            process::exit(0);
+        },
+    }
+}
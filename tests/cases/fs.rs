@@ -46,6 +46,15 @@ pub fn list_home_directory() -> bool {
     check_perm_failure!(fs::read_dir(&home))
 }
 
+pub fn list_exe_directory() -> bool {
+    let exe = env::current_exe().unwrap();
+    check_perm_failure!(fs::read_dir(exe.parent().unwrap()))
+}
+
+pub fn write_dev_null() -> bool {
+    check_perm_failure!(fs::OpenOptions::new().write(true).open("/dev/null"))
+}
+
 fn home_dir() -> PathBuf {
     PathBuf::from(env::var_os("SANDBOX_TEST_HOME").unwrap())
 }
\ No newline at end of file
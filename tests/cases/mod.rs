@@ -9,7 +9,33 @@ macro_rules! check_perm_failure {
 }
 
 mod fs;
+mod net;
 mod rand;
+mod stdio;
+
+use std::{env};
+
+use sandbox::{BrokerServices, Command, Policy};
+
+/// Creates a command that runs the test binary itself as a target confined by `policy`, with the
+/// environment the test cases expect.
+#[allow(dead_code)]
+pub fn target_command(policy: &Policy) -> Command {
+    let mut command = Command::new(env::current_exe().unwrap(), policy);
+    command
+        .env("SANDBOX_TEST_HOME", env::home_dir().unwrap())
+        .env_inherit("RUST_LOG");
+    command
+}
+
+/// Spawns and runs `command`, and checks that the target exits successfully.
+#[allow(dead_code)]
+pub fn run_to_success(broker: &mut BrokerServices, command: &mut Command) {
+    let mut child = command.spawn(broker).unwrap();
+    child.run().unwrap();
+    let exit_code = child.wait().unwrap();
+    assert!(exit_code.success(), "subprocess returned {}", exit_code);
+}
 
 macro_rules! define_cases {
     ($($module:ident::$name:ident),*$(,)*) => {
@@ -51,7 +77,11 @@ macro_rules! define_cases {
 define_cases! {
     fs::create_file_home,
     fs::list_home_directory,
+    fs::list_exe_directory,
     fs::open_extant_file_home,
     fs::open_nonexistent_file_home,
+    fs::write_dev_null,
+    net::connect_loopback,
     rand::os_rng,
+    stdio::write_stdout,
 }
\ No newline at end of file
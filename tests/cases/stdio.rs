@@ -0,0 +1,7 @@
+use std::io::{self, Write};
+
+pub fn write_stdout() -> bool {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    check_perm_failure!(stdout.write_all(b"writing to stdout from sandbox\n").and_then(|_| stdout.flush()))
+}
@@ -36,6 +36,7 @@ fn run_target(mut target: TargetServices) {
 
     let mut cases = TestCases {
         os_rng: true,
+        write_stdout: true,
         .. TestCases::none()
     };
     if cfg!(target_os = "macos") {
@@ -0,0 +1,88 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, io, process};
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy, PolicyPreset};
+
+fn main() {
+    // Skipped, since targets on Windows receive neither arguments nor environment variables
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    env::set_var("SANDBOX_TEST_ENV_A", "a");
+    env::set_var("SANDBOX_TEST_ENV_B", "b");
+    env::set_var("SANDBOX_TEST_OTHER", "other");
+    env::set_var("SANDBOX_TEST_DENIED", "denied");
+    // Neither has any effect on the broker itself, since the loader only reads them at startup
+    env::set_var("LD_PRELOAD", "/nonexistent/preload.so");
+    env::set_var("DYLD_SANDBOX_TEST", "1");
+
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder.deny_env("SANDBOX_TEST_DENIED");
+    let policy = builder.build().unwrap();
+
+    // Patterns select which variables are inherited
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command
+        .env_inherit_matching("SANDBOX_TEST_ENV_*")
+        .args(&["SANDBOX_TEST_ENV_A=a", "SANDBOX_TEST_ENV_B=b", "!SANDBOX_TEST_OTHER"]);
+    run(&mut broker, &mut command);
+
+    // Denied variables are dropped even when a pattern matches them, including those denied by default
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command
+        .env_inherit_matching("*")
+        .args(&["SANDBOX_TEST_ENV_A=a", "SANDBOX_TEST_OTHER=other", "!SANDBOX_TEST_DENIED", "!LD_PRELOAD", "!DYLD_SANDBOX_TEST"]);
+    run(&mut broker, &mut command);
+
+    // Denied variables can't be passed explicitly
+    for &(name, value) in &[("SANDBOX_TEST_DENIED", "denied"), ("LD_PRELOAD", "/nonexistent/preload.so"), ("DYLD_INSERT_LIBRARIES", "/nonexistent/preload.dylib")] {
+        let mut command = Command::new(env::current_exe().unwrap(), &policy);
+        command.env(name, value);
+        assert_eq!(command.spawn(&mut broker).err().map(|err| err.kind()), Some(io::ErrorKind::PermissionDenied), "{} was passed", name);
+        let mut command = Command::new(env::current_exe().unwrap(), &policy);
+        command.env_inherit(name);
+        assert_eq!(command.spawn(&mut broker).err().map(|err| err.kind()), Some(io::ErrorKind::PermissionDenied), "{} was inherited", name);
+    }
+}
+
+fn run(broker: &mut BrokerServices, command: &mut Command) {
+    command.env_inherit("RUST_LOG");
+    let mut child = command.spawn(broker).unwrap();
+    child.run().unwrap();
+    let exit_code = child.wait().unwrap();
+    assert!(exit_code.success(), "subprocess returned {}", exit_code);
+}
+
+// Each argument is either `NAME=VALUE`, for a variable that must be set to that value, or `!NAME`,
+// for one that must not be set
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+
+    let mut success = true;
+    for expectation in env::args().skip(1) {
+        let (name, expected) = if expectation.starts_with("!") {
+            (expectation[1..].to_owned(), None)
+        } else {
+            let separator = expectation.find('=').unwrap();
+            (expectation[..separator].to_owned(), Some(expectation[(separator + 1)..].to_owned()))
+        };
+        let actual = env::var(&name).ok();
+        if actual != expected {
+            eprintln!("expected {} to be {:?}, but it was {:?}", name, expected, actual);
+            success = false;
+        }
+    }
+    if !success {
+        process::exit(1);
+    }
+}
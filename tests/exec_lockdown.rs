@@ -0,0 +1,63 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, fs};
+use std::process::ExitStatus;
+
+use sandbox::{Services, BrokerServices, Command, Policy, PolicyPreset};
+
+// Runs programs that know nothing about the sandbox, so this process is only ever a broker
+fn main() {
+    // Skipped, since lockdown at exec is not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    if env::args().nth(1).map(|x| x == "check-inherited").unwrap_or(false) {
+        check_inherited();
+        return;
+    }
+    match sandbox::init().unwrap() {
+        Services::Broker(mut broker) => {
+            let policy = Policy::compute_only(&mut broker).unwrap();
+            let status = list_home(&mut broker, &policy);
+            assert!(!status.success(), "listing home directory succeeded under ComputeOnly");
+
+            let policy = Policy::builder(&mut broker, PolicyPreset::ReadOnlyFilesystem).build().unwrap();
+            let status = list_home(&mut broker, &policy);
+            assert!(status.success(), "listing home directory failed under ReadOnlyFilesystem: {}", status);
+
+            // The sandbox's own descriptors and variables must not leak into the program
+            let mut command = Command::new(env::current_exe().unwrap(), &policy);
+            command
+                .arg("check-inherited")
+                .lockdown_at_exec(true);
+            let mut child = command.spawn(&mut broker).unwrap();
+            child.run().unwrap();
+            assert!(child.wait().unwrap().success(), "program inherited the sandbox's IPC channel");
+        },
+        Services::Target(_) => panic!("test binary should not be launched as a target"),
+    }
+}
+
+fn list_home(broker: &mut BrokerServices, policy: &Policy) -> ExitStatus {
+    let mut command = Command::new("/bin/ls", policy);
+    command
+        .arg(env::home_dir().unwrap())
+        .lockdown_at_exec(true);
+    let mut child = command.spawn(broker).unwrap();
+    child.run().unwrap();
+    child.wait().unwrap()
+}
+
+fn check_inherited() {
+    for (name, _) in env::vars_os() {
+        assert!(!name.to_string_lossy().starts_with("SANDBOX_"), "inherited environment variable {:?}", name);
+    }
+    // Listing the directory opens one descriptor of its own
+    let fds: Vec<i32> = fs::read_dir("/dev/fd").unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().parse().unwrap())
+        .filter(|&fd| fd > 2)
+        .collect();
+    assert!(fds.len() <= 1, "inherited file descriptors {:?}", fds);
+}
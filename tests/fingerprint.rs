@@ -0,0 +1,102 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::collections::HashSet;
+
+use sandbox::{Services, BrokerServices, Policy, PolicyBuilder, PolicyPreset, PolicyFingerprint, ViolationAction};
+
+fn main() {
+    // Skipped, since presets other than ComputeOnly and path rules are not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(_) => panic!("test binary should not be launched as a target"),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let rules: &[(&str, bool)] = &[
+        ("${JOB_DIR}/input", false),
+        ("${JOB_DIR}/output", true),
+        ("/usr/share", false),
+        ("${HOME}/${CACHE}", true),
+    ];
+    let fingerprint = build(&mut broker, PolicyPreset::ReadOnlyFilesystem, rules);
+
+    // Rule insertion order doesn't matter
+    let reversed: Vec<(&str, bool)> = rules.iter().rev().cloned().collect();
+    assert_eq!(build(&mut broker, PolicyPreset::ReadOnlyFilesystem, &reversed), fingerprint);
+    // Each build of the same policy fills the platform's parameter maps in a different order
+    for _ in 0..16 {
+        assert_eq!(build(&mut broker, PolicyPreset::ReadOnlyFilesystem, rules), fingerprint);
+    }
+    // Anything that changes how the policy is enforced changes the fingerprint
+    let mut different = vec![
+        fingerprint,
+        build(&mut broker, PolicyPreset::ComputeOnly, rules),
+        build(&mut broker, PolicyPreset::Interpreter { install_dir: "/usr/lib".into() }, rules),
+        build(&mut broker, PolicyPreset::Interpreter { install_dir: "/usr/bin".into() }, rules),
+        build(&mut broker, PolicyPreset::ReadOnlyFilesystem, &rules[1..]),
+        build(&mut broker, PolicyPreset::ReadOnlyFilesystem, &[("${JOB_DIR}/input", true)]),
+        build(&mut broker, PolicyPreset::ReadOnlyFilesystem, &[("${JOB_DIR}", false)]),
+        build(&mut broker, PolicyPreset::ReadOnlyFilesystem, &[("${JOB_DIR}/input/", false)]),
+        // A parameter and a literal of the same text must differ
+        build(&mut broker, PolicyPreset::ReadOnlyFilesystem, &[("/${JOB_DIR}", false)]),
+        build(&mut broker, PolicyPreset::ReadOnlyFilesystem, &[("/JOB_DIR", false)]),
+        build(&mut broker, PolicyPreset::ReadOnlyFilesystem, &[]),
+    ];
+    for &action in &[ViolationAction::Kill, ViolationAction::Trap] {
+        let mut builder = builder(&mut broker, PolicyPreset::ReadOnlyFilesystem, rules);
+        builder.on_violation(action);
+        different.push(builder.build().unwrap().fingerprint());
+    }
+    let mut builder = builder(&mut broker, PolicyPreset::ReadOnlyFilesystem, rules);
+    builder.learning_mode(true);
+    different.push(builder.build().unwrap().fingerprint());
+    let mut builder = builder(&mut broker, PolicyPreset::ReadOnlyFilesystem, rules);
+    builder.deny_env("SANDBOX_TEST_*");
+    different.push(builder.build().unwrap().fingerprint());
+    different.extend(platform_variants(&mut broker, rules));
+    let unique: HashSet<PolicyFingerprint> = different.iter().cloned().collect();
+    assert_eq!(unique.len(), different.len(), "distinct policies share a fingerprint: {:?}", different);
+}
+
+// Policies that differ only in platform-specific options
+#[cfg(target_os = "macos")]
+fn platform_variants(broker: &mut BrokerServices, rules: &[(&str, bool)]) -> Vec<PolicyFingerprint> {
+    use sandbox::os::macos::{Access, PolicyBuilderExt};
+
+    let mut builder = builder(broker, PolicyPreset::ReadOnlyFilesystem, rules);
+    builder.set_default_access(Access::Allow);
+    let mut compute_only = Policy::builder(broker, PolicyPreset::ComputeOnly);
+    compute_only.set_default_access(Access::Allow);
+    vec![
+        builder.build().unwrap().fingerprint(),
+        build(broker, PolicyPreset::ComputeOnly, &[]),
+        compute_only.build().unwrap().fingerprint(),
+    ]
+}
+
+#[cfg(not(target_os = "macos"))]
+fn platform_variants(_broker: &mut BrokerServices, _rules: &[(&str, bool)]) -> Vec<PolicyFingerprint> {
+    Vec::new()
+}
+
+fn build(broker: &mut BrokerServices, preset: PolicyPreset, rules: &[(&str, bool)]) -> PolicyFingerprint {
+    builder(broker, preset, rules).build().unwrap().fingerprint()
+}
+
+fn builder(broker: &mut BrokerServices, preset: PolicyPreset, rules: &[(&str, bool)]) -> PolicyBuilder {
+    let mut builder = Policy::builder(broker, preset);
+    for &(path, write) in rules {
+        if write {
+            builder.allow_read_write(path);
+        } else {
+            builder.allow_read(path);
+        }
+    }
+    builder
+}
@@ -0,0 +1,45 @@
+extern crate sandbox;
+extern crate env_logger;
+
+mod cases;
+
+use cases::{TestCases};
+
+use std::{env, process};
+
+use sandbox::{Services, BrokerServices, TargetServices, Policy, PolicyPreset};
+
+fn main() {
+    // Skipped, since the Interpreter preset is not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let install_dir = env::current_exe().unwrap().parent().unwrap().to_owned();
+    let policy = Policy::builder(&mut broker, PolicyPreset::Interpreter { install_dir }).build().unwrap();
+    cases::run_to_success(&mut broker, &mut cases::target_command(&policy));
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+
+    let mut cases = TestCases {
+        list_exe_directory: true,
+        os_rng: true,
+        write_stdout: true,
+        .. TestCases::none()
+    };
+    if cfg!(target_os = "macos") {
+        cases.open_nonexistent_file_home = true; // macOS sandbox doesn't blind open() to files that do not exist
+    }
+    if !cases.run() {
+        process::exit(1);
+    }
+}
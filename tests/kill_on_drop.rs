@@ -0,0 +1,84 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, process, thread};
+use std::process::{Command as StdCommand, Stdio};
+use std::time::{Duration, Instant};
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy};
+
+fn main() {
+    // Skipped, since messaging is not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => if env::args().nth(1).map(|x| x == "orphan").unwrap_or(false) {
+            spawn_orphan(broker)
+        } else {
+            run_broker(broker)
+        },
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+
+    // Dropping the child kills it
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command
+        .env_inherit("RUST_LOG")
+        .kill_on_drop(true);
+    let mut child = command.spawn(&mut broker).unwrap();
+    child.run().unwrap();
+    let ready: Option<()> = child.recv_message().unwrap();
+    assert!(ready.is_some());
+    let id = child.id();
+    drop(child);
+    assert!(!process_exists(id), "child survived being dropped");
+
+    // Targets exit when their broker does, even if it is killed
+    let output = StdCommand::new(env::current_exe().unwrap())
+        .arg("orphan")
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap();
+    let id: u32 = String::from_utf8(output.stdout).unwrap().trim().parse().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while process_exists(id) {
+        assert!(Instant::now() < deadline, "target survived its broker");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Spawns a target and exits without cleaning it up, as if the broker had crashed
+fn spawn_orphan(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command.env_inherit("RUST_LOG");
+    let mut child = command.spawn(&mut broker).unwrap();
+    child.run().unwrap();
+    let ready: Option<()> = child.recv_message().unwrap();
+    assert!(ready.is_some());
+    println!("{}", child.id());
+    process::exit(0);
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+    target.send_message(&()).unwrap();
+    loop {
+        thread::sleep(Duration::from_secs(60));
+    }
+}
+
+fn process_exists(id: u32) -> bool {
+    StdCommand::new("/bin/kill")
+        .args(&["-0", &id.to_string()])
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
@@ -0,0 +1,74 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, thread};
+use std::process::{Command as StdCommand, Stdio};
+use std::time::{Duration, Instant};
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy, PolicyPreset, KillMechanism};
+
+fn main() {
+    // Skipped, since process groups and messaging are not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    // Targets need to be able to start processes of their own
+    let policy = Policy::builder(&mut broker, PolicyPreset::Unrestricted).build().unwrap();
+
+    let (mechanism, grandchild) = spawn_and_kill_tree(&mut broker, &policy, true);
+    assert_eq!(mechanism, KillMechanism::ProcessGroup);
+    wait_for_exit(grandchild);
+
+    let (mechanism, grandchild) = spawn_and_kill_tree(&mut broker, &policy, false);
+    assert_eq!(mechanism, KillMechanism::ProcessOnly);
+    assert!(process_exists(grandchild), "grandchild was killed without a process group");
+    StdCommand::new("/bin/kill").args(&["-9", &grandchild.to_string()]).status().unwrap();
+}
+
+fn spawn_and_kill_tree(broker: &mut BrokerServices, policy: &Policy, new_process_group: bool) -> (KillMechanism, u32) {
+    let mut command = Command::new(env::current_exe().unwrap(), policy);
+    command
+        .env_inherit("RUST_LOG")
+        .new_process_group(new_process_group);
+    let mut child = command.spawn(broker).unwrap();
+    child.run().unwrap();
+    let grandchild: u32 = child.recv_message().unwrap().unwrap();
+    let mechanism = child.kill_tree().unwrap();
+    assert!(!child.wait().unwrap().success());
+    (mechanism, grandchild)
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+    let grandchild = StdCommand::new("/bin/sleep").arg("60").spawn().unwrap();
+    target.send_message(&grandchild.id()).unwrap();
+    loop {
+        thread::sleep(Duration::from_secs(60));
+    }
+}
+
+// The grandchild is reaped by launchd once it is orphaned, which may take a moment
+fn wait_for_exit(id: u32) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while process_exists(id) {
+        assert!(Instant::now() < deadline, "grandchild survived kill_tree");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn process_exists(id: u32) -> bool {
+    StdCommand::new("/bin/kill")
+        .args(&["-0", &id.to_string()])
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
@@ -0,0 +1,81 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, fs, process, thread};
+use std::path::Path;
+use std::time::Duration;
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy, PolicyPreset, LearnedPolicy, Termination};
+
+fn main() {
+    // Skipped, since learning mode is not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let dir = env::temp_dir().join(format!("sandbox_learning_mode_{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let dir = fs::canonicalize(&dir).unwrap();
+    let input = dir.join("input");
+    let output = dir.join("output");
+    fs::write(&input, b"learned").unwrap();
+
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder.learning_mode(true);
+    let policy = builder.build().unwrap();
+    let mut child = spawn(&mut broker, &policy, &input, &output);
+    child.run().unwrap();
+    assert_eq!(child.wait_termination().unwrap(), Termination::Exited(0));
+
+    // Reports reach the system log asynchronously
+    let mut learned = child.learned_policy().unwrap();
+    for _ in 0..20 {
+        if learned_accesses(&learned, &input, &output) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(250));
+        learned = child.learned_policy().unwrap();
+    }
+    assert!(learned_accesses(&learned, &input, &output), "accesses are missing from the learned policy:\n{}", learned);
+    assert_eq!(learned.preset, PolicyPreset::ComputeOnly);
+    assert!(!learned.read.iter().any(|path| path.starts_with(&output)), "write was also learned as a read:\n{}", learned);
+
+    // The learned policy is enough for the child to run confined
+    fs::remove_file(&output).unwrap();
+    let policy = learned.builder(&mut broker).build().unwrap();
+    let mut child = spawn(&mut broker, &policy, &input, &output);
+    child.run().unwrap();
+    assert_eq!(child.wait_termination().unwrap(), Termination::Exited(0));
+    assert!(child.learned_policy().is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn spawn(broker: &mut BrokerServices, policy: &Policy, input: &Path, output: &Path) -> sandbox::Child {
+    let mut command = Command::new(env::current_exe().unwrap(), policy);
+    command
+        .arg(input)
+        .arg(output)
+        .env_inherit("RUST_LOG");
+    command.spawn(broker).unwrap()
+}
+
+fn learned_accesses(learned: &LearnedPolicy, input: &Path, output: &Path) -> bool {
+    learned.read.iter().any(|path| input.starts_with(path)) && learned.read_write.iter().any(|path| output.starts_with(path))
+}
+
+fn run_target(mut target: TargetServices) {
+    let input = env::args_os().nth(1).unwrap();
+    let output = env::args_os().nth(2).unwrap();
+    target.lockdown();
+
+    let contents = fs::read(&input).unwrap();
+    fs::write(&output, &contents).unwrap();
+}
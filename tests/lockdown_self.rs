@@ -0,0 +1,52 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, io};
+use std::fs::File;
+use std::process::Command as StdCommand;
+
+use sandbox::{Services, BrokerServices, Policy, PolicyPreset};
+
+fn main() {
+    env_logger::init();
+    let broker = match sandbox::init().unwrap() {
+        Services::Broker(broker) => broker,
+        Services::Target(_) => panic!("test was spawned as a target"),
+    };
+    match env::args().nth(1) {
+        None => run_parent(broker),
+        Some(ref behavior) if behavior == "confined" => run_confined(broker),
+        Some(behavior) => panic!("unknown behavior {}", behavior),
+    }
+}
+
+fn run_parent(mut broker: BrokerServices) {
+    if cfg!(windows) {
+        let policy = Policy::compute_only(&mut broker).unwrap();
+        assert!(broker.lockdown_self(&policy).is_err());
+        return;
+    }
+
+    // Rejected policies leave the process as it was
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder.learning_mode(true);
+    let policy = builder.build().unwrap();
+    assert_eq!(broker.lockdown_self(&policy).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    File::open(env::current_exe().unwrap()).unwrap();
+
+    // The process that locks itself down is a broker, so it is started without the sandbox
+    let status = StdCommand::new(env::current_exe().unwrap())
+        .arg("confined")
+        .status()
+        .unwrap();
+    assert!(status.success(), "confined process failed with {}", status);
+}
+
+fn run_confined(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+    broker.lockdown_self(&policy).unwrap();
+    match File::open(env::current_exe().unwrap()) {
+        Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {},
+        result => panic!("file access after lockdown_self was not denied: {:?}", result.map(|_| ())),
+    }
+}
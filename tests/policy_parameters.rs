@@ -0,0 +1,75 @@
+extern crate sandbox;
+extern crate env_logger;
+
+mod cases;
+
+use cases::{TestCases};
+
+use std::{env, io, process};
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy, PolicyPreset};
+
+fn main() {
+    // Skipped, since path rules are not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder
+        .allow_read("${TEST_HOME}")
+        .allow_read("${EXE_DIR}");
+    let policy = builder.build().unwrap();
+
+    // Spawning must fail while a referenced parameter is unbound
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    expect_invalid_input(&mut broker, &mut command, "an unbound policy parameter");
+
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command
+        .param("TEST_HOME", env::home_dir().unwrap())
+        .param("NOT-A-NAME", "/");
+    expect_invalid_input(&mut broker, &mut command, "an invalid policy parameter name");
+
+    if cfg!(unix) {
+        let mut command = Command::new(env::current_exe().unwrap(), &policy);
+        command.param("TEST_HOME", "/tmp\0/evil");
+        expect_invalid_input(&mut broker, &mut command, "a NUL character in a policy parameter");
+    }
+
+    let mut command = cases::target_command(&policy);
+    command.param("TEST_HOME", env::home_dir().unwrap());
+    cases::run_to_success(&mut broker, &mut command);
+}
+
+fn expect_invalid_input(broker: &mut BrokerServices, command: &mut Command, reason: &str) {
+    match command.spawn(broker) {
+        Err(ref err) if err.kind() == io::ErrorKind::InvalidInput => {},
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("spawn succeeded with {}", reason),
+    }
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+
+    let cases = TestCases {
+        list_home_directory: true,
+        list_exe_directory: true,
+        open_extant_file_home: true,
+        open_nonexistent_file_home: true,
+        os_rng: true,
+        write_stdout: true,
+        .. TestCases::none()
+    };
+    if !cases.run() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,45 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy};
+
+const ALLOCATION_SIZE: usize = 64 * 1024 * 1024;
+
+fn main() {
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command.env_inherit("RUST_LOG");
+    let mut child = command.spawn(&mut broker).unwrap();
+    assert!(child.resource_usage().is_none());
+    child.run().unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let usage = child.resource_usage().expect("no resource usage after wait");
+    assert!(usage.user_time >= Duration::from_millis(100), "user time too low: {:?}", usage);
+    assert!(usage.max_rss >= ALLOCATION_SIZE as u64, "max RSS too low: {:?}", usage);
+    assert!(usage.minor_page_faults + usage.major_page_faults > 0, "no page faults: {:?}", usage);
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+
+    // Touch every page so it counts towards the resident set
+    let memory = vec![1u8; ALLOCATION_SIZE];
+    let start = Instant::now();
+    let mut sum = 0u64;
+    while start.elapsed() < Duration::from_millis(300) {
+        sum = memory.iter().step_by(4096).fold(sum, |sum, &x| sum.wrapping_add(x as u64));
+    }
+    assert!(sum > 0);
+}
@@ -0,0 +1,45 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{process};
+
+use sandbox::{Services, BrokerServices, TargetServices, Policy, FunctionRegistry};
+
+fn main() {
+    // Skipped, since messaging is not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+
+    let sum: Result<i32, String> = broker.call(&policy, "add", &(2, 3)).unwrap();
+    assert_eq!(sum, Ok(5));
+
+    let result: Result<(), String> = broker.call(&policy, "panic", &"boom").unwrap();
+    let message = result.unwrap_err();
+    assert!(message.contains("boom"), "unexpected panic message {:?}", message);
+
+    let result: Result<(), String> = broker.call(&policy, "missing", &()).unwrap();
+    assert!(result.is_err(), "calling an unregistered function succeeded");
+}
+
+fn run_target(mut target: TargetServices) {
+    let mut registry = FunctionRegistry::new();
+    registry
+        .register("add", |(a, b): (i32, i32)| a + b)
+        .register("panic", |message: String| -> () { panic!("{}", message) });
+
+    target.lockdown();
+    if let Err(err) = target.serve(&registry) {
+        eprintln!("error serving sandboxed functions: {}", err);
+        process::exit(1);
+    }
+}
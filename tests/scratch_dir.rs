@@ -0,0 +1,81 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, fs, io, process, thread};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Child, Policy, PolicyPreset};
+
+fn main() {
+    // Skipped, since scratch directories are not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+
+    let (mut child, scratch_dir) = spawn_child(&mut broker, &policy, "exit", true);
+    assert!(scratch_dir.is_dir());
+    assert!(child.wait().unwrap().success());
+    assert!(!scratch_dir.exists(), "scratch directory survived wait");
+
+    // Each child gets a fresh directory, which is removed even if the child crashed
+    let (mut child, crashed_scratch_dir) = spawn_child(&mut broker, &policy, "abort", true);
+    assert_ne!(crashed_scratch_dir, scratch_dir);
+    assert!(!child.wait().unwrap().success());
+    assert!(!crashed_scratch_dir.exists(), "scratch directory survived crash");
+
+    let (child, scratch_dir) = spawn_child(&mut broker, &policy, "hang", true);
+    drop(child);
+    assert!(!scratch_dir.exists(), "scratch directory survived drop");
+
+    // The child must not outlive its directory, or it could recreate it
+    let (child, scratch_dir) = spawn_child(&mut broker, &policy, "hang", false);
+    drop(child);
+    assert!(!scratch_dir.exists(), "scratch directory survived drop without kill_on_drop");
+
+    // The directory is passed in TMPDIR, which can't be set as well
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command
+        .env("TMPDIR", env::temp_dir())
+        .scratch_dir(1 << 20);
+    assert_eq!(command.spawn(&mut broker).err().map(|err| err.kind()), Some(io::ErrorKind::InvalidInput));
+}
+
+fn spawn_child(broker: &mut BrokerServices, policy: &Policy, behavior: &str, kill_on_drop: bool) -> (Child, PathBuf) {
+    let mut command = Command::new(env::current_exe().unwrap(), policy);
+    command
+        .arg(behavior)
+        .env_inherit("RUST_LOG")
+        .scratch_dir(1 << 20)
+        .kill_on_drop(kill_on_drop);
+    let mut child = command.spawn(broker).unwrap();
+    child.run().unwrap();
+    let scratch_dir: PathBuf = child.recv_message().unwrap().unwrap();
+    (child, scratch_dir)
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+    let scratch_dir = PathBuf::from(env::var_os("TMPDIR").unwrap());
+    let file_path = scratch_dir.join("scratch.txt");
+    fs::write(&file_path, "scratch").unwrap();
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "scratch");
+    fs::create_dir(scratch_dir.join("nested")).unwrap();
+    target.send_message(&scratch_dir).unwrap();
+    match env::args().nth(1).unwrap().as_str() {
+        "exit" => {},
+        "abort" => process::abort(),
+        _ => loop {
+            thread::sleep(Duration::from_secs(60));
+        },
+    }
+}
@@ -0,0 +1,59 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, io};
+use std::process::{Command as StdCommand, Stdio};
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy, PolicyPreset};
+
+const SETUID_PROGRAM: &str = "/usr/bin/sudo";
+
+fn main() {
+    // Skipped, since setuid programs and lockdown at exec only exist on Unix
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    // Even a policy that allows everything else must not allow gaining privileges, nor may a
+    // policy in learning mode, which enforces nothing else
+    let policy = Policy::builder(&mut broker, PolicyPreset::Unrestricted).build().unwrap();
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder.learning_mode(true);
+    let learning_policy = builder.build().unwrap();
+
+    for policy in &[&policy, &learning_policy] {
+        let mut command = Command::new(env::current_exe().unwrap(), policy);
+        command.env_inherit("RUST_LOG");
+        let mut child = command.spawn(&mut broker).unwrap();
+        child.run().unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+
+    // The program itself runs before the policy applies, so it is checked when spawning
+    let mut command = Command::new(SETUID_PROGRAM, &policy);
+    command
+        .args(&["-n", "true"])
+        .lockdown_at_exec(true);
+    match command.spawn(&mut broker) {
+        Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {},
+        Err(err) => panic!("unexpected error when spawning setuid program: {}", err),
+        Ok(_) => panic!("spawned setuid program"),
+    }
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+    let result = StdCommand::new(SETUID_PROGRAM)
+        .args(&["-n", "true"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    assert!(result.is_err(), "sandboxed process executed setuid program: {:?}", result);
+}
@@ -0,0 +1,153 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, process, thread};
+use std::collections::HashMap;
+use std::process::{Command as StdCommand, Stdio};
+use std::time::{Duration, Instant};
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy, PolicyPreset, Supervisor, SupervisorEvent};
+
+fn main() {
+    // Skipped, since messaging, process groups and zygotes are not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+    let mut supervisor = Supervisor::new(&broker).unwrap();
+    let mut behaviors = HashMap::new();
+    for &behavior in ["exit", "abort", "hang"].iter() {
+        let mut command = Command::new(env::current_exe().unwrap(), &policy);
+        command
+            .arg(behavior)
+            .env_inherit("RUST_LOG");
+        let mut child = command.spawn(&mut broker).unwrap();
+        child.run().unwrap();
+        let id = if behavior == "hang" {
+            supervisor.add_with_timeout(child, Duration::from_millis(500)).unwrap()
+        } else {
+            supervisor.add(child).unwrap()
+        };
+        behaviors.insert(id, behavior);
+    }
+
+    let mut messages = Vec::new();
+    let mut terminated = HashMap::new();
+    while let Some(event) = supervisor.next_event().unwrap() {
+        match event {
+            SupervisorEvent::Message { id, message } => messages.push((behaviors[&id], message)),
+            SupervisorEvent::Exited { id, status } => {
+                assert_eq!(status.code(), Some(3));
+                terminated.insert(behaviors[&id], "exited");
+            },
+            SupervisorEvent::Signaled { id, .. } => { terminated.insert(behaviors[&id], "signaled"); },
+            SupervisorEvent::Violation { id } => { terminated.insert(behaviors[&id], "violation"); },
+            SupervisorEvent::TimedOut { id } => { terminated.insert(behaviors[&id], "timed out"); },
+            SupervisorEvent::Failed { id, error } => panic!("supervising {} failed: {}", behaviors[&id], error),
+        }
+    }
+    assert_eq!(messages, vec![("exit", "\"goodbye\"".to_owned())]);
+    assert_eq!(terminated["exit"], "exited");
+    assert_eq!(terminated["abort"], "signaled");
+    assert_eq!(terminated["hang"], "timed out");
+    assert!(supervisor.is_empty());
+
+    // kill_all kills children that are still running, without reporting them
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command.arg("hang");
+    let mut child = command.spawn(&mut broker).unwrap();
+    child.run().unwrap();
+    let mut supervisor = Supervisor::new(&broker).unwrap();
+    supervisor.add(child).unwrap();
+    supervisor.kill_all();
+    assert!(supervisor.next_event().unwrap().is_none());
+
+    // Dropping the supervisor kills its children along with their process groups. Targets need
+    // to be able to start processes of their own for this.
+    let unrestricted = Policy::builder(&mut broker, PolicyPreset::Unrestricted).build().unwrap();
+    let mut command = Command::new(env::current_exe().unwrap(), &unrestricted);
+    command
+        .arg("spawn")
+        .env_inherit("RUST_LOG")
+        .new_process_group(true);
+    let mut child = command.spawn(&mut broker).unwrap();
+    child.run().unwrap();
+    let grandchild: u32 = child.recv_message().unwrap().unwrap();
+    let mut supervisor = Supervisor::new(&broker).unwrap();
+    supervisor.add(child).unwrap();
+    drop(supervisor);
+    wait_for_exit(grandchild);
+
+    // Targets forked by a zygote are reported once the zygote has reaped them, which may be after
+    // the kernel reports their exit
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command
+        .arg("exit")
+        .env_inherit("RUST_LOG");
+    let mut zygote = command.spawn_zygote(&mut broker).unwrap();
+    let mut supervisor = Supervisor::new(&broker).unwrap();
+    for _ in 0..3 {
+        let mut child = zygote.fork(&mut broker, &policy).unwrap();
+        child.run().unwrap();
+        supervisor.add(child).unwrap();
+    }
+    let mut exited = 0;
+    while let Some(event) = supervisor.next_event().unwrap() {
+        match event {
+            SupervisorEvent::Message { .. } => {},
+            SupervisorEvent::Exited { status, .. } => {
+                assert_eq!(status.code(), Some(3));
+                exited += 1;
+            },
+            event => panic!("unexpected event for forked target: {:?}", event),
+        }
+    }
+    assert_eq!(exited, 3);
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+    match env::args().nth(1).unwrap().as_str() {
+        "exit" => {
+            target.send_message(&"goodbye").unwrap();
+            process::exit(3);
+        },
+        "abort" => process::abort(),
+        "spawn" => {
+            let grandchild = StdCommand::new("/bin/sleep").arg("60").spawn().unwrap();
+            target.send_message(&grandchild.id()).unwrap();
+            loop {
+                thread::sleep(Duration::from_secs(60));
+            }
+        },
+        _ => loop {
+            thread::sleep(Duration::from_secs(60));
+        },
+    }
+}
+
+// The grandchild is reaped by launchd once it is orphaned, which may take a moment
+fn wait_for_exit(id: u32) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while process_exists(id) {
+        assert!(Instant::now() < deadline, "grandchild survived the supervisor");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn process_exists(id: u32) -> bool {
+    StdCommand::new("/bin/kill")
+        .args(&["-0", &id.to_string()])
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
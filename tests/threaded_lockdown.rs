@@ -0,0 +1,49 @@
+extern crate sandbox;
+extern crate env_logger;
+
+mod cases;
+
+use std::{env, fs, io, process, thread};
+use std::sync::mpsc;
+
+use sandbox::{Services, BrokerServices, TargetServices, Policy};
+
+fn main() {
+    // Skipped, since targets on Windows receive no environment variables, which pass the home directory
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+    cases::run_to_success(&mut broker, &mut cases::target_command(&policy));
+}
+
+fn run_target(mut target: TargetServices) {
+    let home = env::var_os("SANDBOX_TEST_HOME").unwrap();
+
+    // Start a thread before lockdown, and have it try to access the filesystem afterwards
+    let (locked_tx, locked_rx) = mpsc::channel();
+    let sibling = thread::spawn(move || {
+        locked_rx.recv().unwrap();
+        match fs::read_dir(&home) {
+            Ok(_) => false,
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => true,
+            Err(err) => panic!("unexpected error {}", err),
+        }
+    });
+
+    target.lockdown();
+    locked_tx.send(()).unwrap();
+
+    if !sibling.join().unwrap() {
+        eprintln!("thread started before lockdown was not confined");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,86 @@
+extern crate sandbox;
+extern crate env_logger;
+#[cfg(target_os = "macos")]
+extern crate libc;
+
+use std::{env, process};
+use std::fs::File;
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Child, Policy, PolicyPreset, ViolationAction, Termination};
+
+fn main() {
+    // Skipped, since violation actions other than Deny are not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder.on_violation(ViolationAction::Kill);
+    let killing_policy = builder.build().unwrap();
+    let denying_policy = Policy::compute_only(&mut broker).unwrap();
+
+    assert_eq!(run_child(&mut broker, &killing_policy, "violate"), Termination::Violation);
+    assert_eq!(run_child(&mut broker, &denying_policy, "violate"), Termination::Exited(0));
+    assert_eq!(run_child(&mut broker, &killing_policy, "exit"), Termination::Exited(4));
+    match run_child(&mut broker, &killing_policy, "abort") {
+        Termination::Signaled(_) => {},
+        termination => panic!("aborted child terminated with {:?}", termination),
+    }
+    // The sandbox never sends the violation signal to a child whose policy only denies operations
+    #[cfg(target_os = "macos")]
+    assert_eq!(run_child(&mut broker, &denying_policy, "sigsys"), Termination::Signaled(libc::SIGSYS));
+
+    // Trapping violations reports the denied syscall before the child dies
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder.on_violation(ViolationAction::Trap);
+    let trapping_policy = builder.build().unwrap();
+    let mut child = spawn_child(&mut broker, &trapping_policy, "violate");
+    child.run().unwrap();
+    assert_eq!(child.wait_termination().unwrap(), Termination::Violation);
+    let report = child.violation_report().expect("no violation report for trapped child");
+    if cfg!(target_arch = "x86_64") {
+        assert!(report.syscall_name.map(|x| x.starts_with("open")).unwrap_or(false), "unexpected syscall in {:?}", report);
+    }
+    let mut child = spawn_child(&mut broker, &killing_policy, "violate");
+    child.run().unwrap();
+    child.wait().unwrap();
+    assert!(child.violation_report().is_none());
+}
+
+fn run_child(broker: &mut BrokerServices, policy: &Policy, behavior: &str) -> Termination {
+    let mut child = spawn_child(broker, policy, behavior);
+    child.run().unwrap();
+    child.wait_termination().unwrap()
+}
+
+fn spawn_child(broker: &mut BrokerServices, policy: &Policy, behavior: &str) -> Child {
+    let mut command = Command::new(env::current_exe().unwrap(), policy);
+    command
+        .arg(behavior)
+        .env("SANDBOX_TEST_HOME", env::home_dir().unwrap())
+        .env_inherit("RUST_LOG");
+    command.spawn(broker).unwrap()
+}
+
+fn run_target(mut target: TargetServices) {
+    let home = env::var_os("SANDBOX_TEST_HOME").unwrap();
+    target.lockdown();
+    match env::args().nth(1).unwrap().as_str() {
+        "violate" => {
+            assert!(File::open(&home).is_err());
+        },
+        "exit" => process::exit(4),
+        #[cfg(target_os = "macos")]
+        "sigsys" => unsafe {
+            libc::raise(libc::SIGSYS);
+        },
+        _ => process::abort(),
+    }
+}
@@ -0,0 +1,77 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{io, process, thread};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use sandbox::{Services, BrokerServices, TargetServices, Policy, FunctionRegistry, WorkerPool, WorkerPoolConfig};
+
+fn main() {
+    // Skipped, since messaging is not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+    let pool = WorkerPool::new(&mut broker, &policy, WorkerPoolConfig {
+        size: 2,
+        max_jobs_per_worker: Some(3),
+        job_timeout: Some(Duration::from_secs(1)),
+    }).unwrap();
+
+    for i in 0..4 {
+        for j in 0..5 {
+            let sum: Result<i32, String> = pool.call(&mut broker, "add", &(i, j)).unwrap();
+            assert_eq!(sum, Ok(i + j));
+        }
+    }
+
+    // Failing to serialize the argument or deserialize the result doesn't affect the worker
+    let mut unserializable = BTreeMap::new();
+    unserializable.insert(vec![1u8], 2);
+    match pool.call::<_, i32>(&mut broker, "add", &unserializable) {
+        Err(ref err) if err.kind() == io::ErrorKind::InvalidInput => {},
+        result => panic!("unserializable argument was not rejected: {:?}", result),
+    }
+    match pool.call::<_, String>(&mut broker, "add", &(1, 2)) {
+        Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {},
+        result => panic!("undeserializable result was not rejected: {:?}", result),
+    }
+    let metrics = pool.metrics();
+    assert_eq!(metrics.workers_crashed, 0, "workers were replaced after a serialization error: {:?}", metrics);
+    assert_eq!(metrics.jobs_failed, 0);
+
+    match pool.call::<_, ()>(&mut broker, "sleep", &10u64) {
+        Err(ref err) if err.kind() == io::ErrorKind::TimedOut => {},
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("job completed despite exceeding its timeout"),
+    }
+
+    let metrics = pool.metrics();
+    assert_eq!(metrics.jobs_completed, 21);
+    assert_eq!(metrics.jobs_timed_out, 1);
+    assert_eq!(metrics.workers_crashed, 1);
+    assert!(metrics.workers_recycled >= 6, "workers were not recycled: {:?}", metrics);
+    assert_eq!(metrics.queue_depth, 0);
+}
+
+fn run_target(mut target: TargetServices) {
+    let mut registry = FunctionRegistry::new();
+    registry
+        .register("add", |(a, b): (i32, i32)| a + b)
+        .register("sleep", |seconds: u64| thread::sleep(Duration::from_secs(seconds)));
+
+    target.lockdown();
+    if let Err(err) = target.serve(&registry) {
+        eprintln!("error serving sandboxed functions: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,72 @@
+extern crate sandbox;
+extern crate env_logger;
+
+use std::{env, io, process};
+
+use sandbox::{Services, BrokerServices, TargetServices, Command, Policy, PolicyPreset};
+
+fn main() {
+    // Skipped, since zygotes are not supported on Windows
+    if cfg!(windows) {
+        return;
+    }
+    env_logger::init();
+    match sandbox::init().unwrap() {
+        Services::Broker(broker) => run_broker(broker),
+        Services::Target(target) => run_target(target),
+    }
+}
+
+fn run_broker(mut broker: BrokerServices) {
+    let policy = Policy::compute_only(&mut broker).unwrap();
+    let mut command = Command::new(env::current_exe().unwrap(), &policy);
+    command.env_inherit("RUST_LOG");
+    let mut zygote = command.spawn_zygote(&mut broker).unwrap();
+
+    let mut children: Vec<_> = (0..3).map(|_| {
+        let mut child = zygote.fork(&mut broker, &policy).unwrap();
+        child.run().unwrap();
+        child
+    }).collect();
+    for (i, child) in children.iter_mut().enumerate() {
+        child.send_message(&(i as u32)).unwrap();
+        let doubled: Option<u32> = child.recv_message().unwrap();
+        assert_eq!(doubled, Some(i as u32 * 2));
+        child.send_message(&u32::max_value()).unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+
+    // Forked targets are managed like any other child
+    let mut child = zygote.fork(&mut broker, &policy).unwrap();
+    child.run().unwrap();
+    child.kill().unwrap();
+    assert!(!child.wait().unwrap().success());
+
+    // Forked targets can't be given more rights than the zygote's command allows
+    let network_client = Policy::builder(&mut broker, PolicyPreset::NetworkClient).build().unwrap();
+    assert!(zygote.fork(&mut broker, &network_client).is_err());
+
+    // Nor can `..` take a rule outside the paths the command allows
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder.allow_read_write("${JOB_DIR}");
+    let job_policy = builder.build().unwrap();
+    let mut command = Command::new(env::current_exe().unwrap(), &job_policy);
+    command
+        .param("JOB_DIR", env::temp_dir())
+        .env_inherit("RUST_LOG");
+    let mut zygote = command.spawn_zygote(&mut broker).unwrap();
+    let mut builder = Policy::builder(&mut broker, PolicyPreset::ComputeOnly);
+    builder.allow_read_write("${JOB_DIR}/../");
+    let escaping_policy = builder.build().unwrap();
+    assert_eq!(zygote.fork(&mut broker, &escaping_policy).err().map(|err| err.kind()), Some(io::ErrorKind::InvalidInput));
+}
+
+fn run_target(mut target: TargetServices) {
+    target.lockdown();
+    loop {
+        match target.recv_message::<u32>().unwrap() {
+            Some(value) if value != u32::max_value() => target.send_message(&(value * 2)).unwrap(),
+            _ => process::exit(0),
+        }
+    }
+}